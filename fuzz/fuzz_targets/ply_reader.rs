@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut stream = std::io::BufReader::new(data);
+    let _ = spz::ply_format::load_ply_stream(&mut stream);
+});
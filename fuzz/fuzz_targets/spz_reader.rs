@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use spz::spz_reader::{SPZReader, SPZReaderOptions};
+
+fuzz_target!(|data: &[u8]| {
+    // Keep crafted headers from spending the whole run on huge, mostly empty allocations.
+    let options = SPZReaderOptions::default()
+        .max_points(1 << 16)
+        .max_uncompressed_size(1 << 24);
+    let _ = SPZReader::new_from_slice(data, options).read();
+});
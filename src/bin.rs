@@ -1,13 +1,12 @@
-mod hilbert_curve;
-
 use anyhow::Result;
 use clap::{Parser, Subcommand, ValueEnum};
 use core::f32;
 use hilbert_curve::hilbert_sort;
-use ply_format::{load_ply, write_ply, PlyEncoding};
-use spz::{unpacked_gaussian::UnpackedGaussian, *};
-use spz_format::write_spz;
+use ply_format::{load_ply_with_report, write_ply, PlyEncoding, PlyWriterOptions};
+use spz::*;
+use spz_format::{write_spz, Columns};
 use spz_reader::*;
+use spz_writer::SPZWriterOptions;
 use std::path::{Path, PathBuf};
 use vek::Vec3;
 
@@ -20,7 +19,8 @@ enum Commands {
         input: PathBuf,
 
         #[arg(value_name = "OUTPUT")]
-        /// The output .spz file
+        /// The output .spz or .ply file. A .compressed.ply file is written in the SuperSplat
+        /// compressed PLY layout.
         output: PathBuf,
 
         #[arg(short, long)]
@@ -31,15 +31,53 @@ enum Commands {
         uncompressed: bool,
 
         #[arg(short, long, default_value = "false")]
-        /// Do not include spherical harmonics in the output.
+        /// Do not include spherical harmonics in the output. Same as `--max-sh-degree 0`.
         omit_spherical_harmonics: bool,
 
+        #[arg(long, default_value = "3")]
+        /// The highest spherical harmonics degree to write (0-3). Higher degrees are truncated.
+        max_sh_degree: u8,
+
         #[arg(long, default_value = "false")]
         /// Sort the gaussians using a hilbert curve. This may improve compression. Experimental.
         use_hilbert_sort: bool,
 
         #[arg(short, long, default_value = "binary-big-endian")]
         ply_encoding: PlyEncoding,
+
+        #[arg(long, default_value = "false")]
+        /// Write PLY positions as double instead of float.
+        ply_double_positions: bool,
+
+        #[arg(long, default_value = "3")]
+        /// The SPZ version to write (2 or 3).
+        spz_version: u32,
+
+        #[arg(long, default_value = "5")]
+        /// Bits kept per band 1 spherical harmonics coefficient in SPZ output (1-8).
+        sh1_bits: u8,
+
+        #[arg(long, default_value = "4")]
+        /// Bits kept per higher band spherical harmonics coefficient in SPZ output (1-8).
+        sh_rest_bits: u8,
+
+        #[arg(long)]
+        /// The fractional bits of SPZ fixed point positions (0-23). Defaults to the most precise
+        /// value that fits the largest coordinate.
+        fractional_bits: Option<u8>,
+
+        #[arg(long, default_value = "9")]
+        /// The gzip compression level for SPZ output (0-9).
+        compression_level: u32,
+
+        #[arg(long, default_value = "1")]
+        /// The number of threads to compress SPZ output with, or 0 for all cores. More than one
+        /// thread writes multi-member gzip.
+        threads: usize,
+
+        #[arg(long, default_value = "false")]
+        /// Write SPZ output as indexed gzip blocks, so columns can be decompressed independently.
+        block_index: bool,
     },
 
     Info {
@@ -94,17 +132,38 @@ fn main() {
             limit,
             uncompressed,
             omit_spherical_harmonics,
+            max_sh_degree,
             use_hilbert_sort,
             ply_encoding,
+            ply_double_positions,
+            spz_version,
+            sh1_bits,
+            sh_rest_bits,
+            fractional_bits,
+            compression_level,
+            threads,
+            block_index,
         } => {
             convert(
                 &input,
                 &output,
                 limit,
                 uncompressed,
-                omit_spherical_harmonics,
+                if omit_spherical_harmonics {
+                    0
+                } else {
+                    max_sh_degree
+                },
                 use_hilbert_sort,
                 ply_encoding,
+                ply_double_positions,
+                spz_version,
+                sh1_bits,
+                sh_rest_bits,
+                fractional_bits,
+                compression_level,
+                threads,
+                block_index,
             )
             .unwrap();
         }
@@ -130,29 +189,47 @@ fn main() {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn convert(
     input: &Path,
     output: &Path,
     limit: Option<usize>,
     uncompressed: bool,
-    omit_spherical_harmonics: bool,
+    max_sh_degree: u8,
     use_hilbert_sort: bool,
     ply_encoding: PlyEncoding,
+    ply_double_positions: bool,
+    spz_version: u32,
+    sh1_bits: u8,
+    sh_rest_bits: u8,
+    fractional_bits: Option<u8>,
+    compression_level: u32,
+    threads: usize,
+    block_index: bool,
 ) -> Result<()> {
-    let mut gaussians = load(input)?;
+    let mut cloud = load(input)?;
     if let Some(limit) = limit {
-        gaussians.truncate(limit);
+        cloud.truncate(limit);
     }
     if use_hilbert_sort {
-        gaussians = hilbert_sort(&gaussians, |g| g.position);
+        let indices = hilbert_sort(&(0..cloud.len()).collect(), |&i| cloud.positions[i]);
+        cloud = cloud.permute(&indices)?;
     }
 
     let options = SaveOptions {
         compressed: !uncompressed,
-        omit_spherical_harmonics,
+        max_sh_degree,
         ply_encoding,
+        ply_double_positions,
+        spz_version,
+        sh1_bits,
+        sh_rest_bits,
+        fractional_bits,
+        compression_level,
+        threads,
+        block_index,
     };
-    save(gaussians, output, &options)?;
+    save(&cloud, output, &options)?;
     Ok(())
 }
 
@@ -160,15 +237,16 @@ fn info(input: &Path) -> Result<()> {
     let mut reader = SPZReader::new_from_path(input, SPZReaderOptions::default())?;
 
     let header = reader.read_header()?;
-    let gaussians = reader.read_gaussians()?;
+    let positions = reader.read_columns(Columns::POSITIONS)?.positions;
     println!("{:#?}", header);
+    println!("Antialiased: {}", header.is_antialiased());
 
     // compute bounding box for all gaussians positions
     let mut min = Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY);
     let mut max = Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
-    for g in gaussians.iter() {
-        min = Vec3::partial_min(min, g.position);
-        max = Vec3::partial_max(max, g.position);
+    for position in positions {
+        min = Vec3::partial_min(min, position);
+        max = Vec3::partial_max(max, position);
     }
     println!("Bounding box: min: {}, max: {}", min, max);
     println!("Center: {}", (min + max) / 2.0);
@@ -184,11 +262,12 @@ enum DumpFormat {
 }
 
 fn dump(input: &Path, limit: Option<usize>, format: DumpFormat) -> Result<()> {
-    let mut gaussians = load(input)?;
+    let mut cloud = load(input)?;
 
     if let Some(limit) = limit {
-        gaussians.truncate(limit);
+        cloud.truncate(limit);
     }
+    let gaussians = cloud.to_gaussians();
 
     match format {
         DumpFormat::Debug => {
@@ -209,7 +288,7 @@ fn dump(input: &Path, limit: Option<usize>, format: DumpFormat) -> Result<()> {
     Ok(())
 }
 
-fn load(input: &Path) -> Result<Vec<UnpackedGaussian>> {
+fn load(input: &Path) -> Result<GaussianCloud> {
     let extension = input
         .extension()
         .and_then(|s| s.to_str())
@@ -217,9 +296,19 @@ fn load(input: &Path) -> Result<Vec<UnpackedGaussian>> {
     match extension {
         "spz" => {
             let mut reader = SPZReader::new_from_path(input, SPZReaderOptions::default())?;
-            reader.read()
+            Ok(reader.read()?)
+        }
+        "ply" => {
+            let (cloud, report) = load_ply_with_report(input)?;
+            if report.position_rounding_error > 0.0 {
+                // On stderr, so `dump --format json` output stays valid.
+                eprintln!(
+                    "Warning: positions were rounded to f32 by up to {}",
+                    report.position_rounding_error
+                );
+            }
+            Ok(cloud)
         }
-        "ply" => load_ply(input),
         _ => panic!("Unsupported file extension"),
     }
 }
@@ -227,30 +316,68 @@ fn load(input: &Path) -> Result<Vec<UnpackedGaussian>> {
 #[derive(Debug, Default)]
 struct SaveOptions {
     compressed: bool,
-    omit_spherical_harmonics: bool,
+    max_sh_degree: u8,
     ply_encoding: PlyEncoding,
+    ply_double_positions: bool,
+    spz_version: u32,
+    sh1_bits: u8,
+    sh_rest_bits: u8,
+    fractional_bits: Option<u8>,
+    compression_level: u32,
+    threads: usize,
+    block_index: bool,
 }
 
-fn save(gaussians: Vec<UnpackedGaussian>, output: &Path, options: &SaveOptions) -> Result<()> {
+fn save(cloud: &GaussianCloud, output: &Path, options: &SaveOptions) -> Result<()> {
     let extension = output
         .extension()
         .and_then(|s| s.to_str())
         .ok_or(anyhow::anyhow!("No extension"))?;
     match extension {
-        "spz" => write_spz(
-            gaussians,
+        "spz" => {
+            let report = write_spz(
+                cloud,
+                output,
+                options.compressed,
+                SPZWriterOptions {
+                    max_sh_degree: options.max_sh_degree,
+                    version: options.spz_version,
+                    sh1_bits: options.sh1_bits,
+                    sh_rest_bits: options.sh_rest_bits,
+                    fractional_bits: options.fractional_bits,
+                    compression_level: options.compression_level,
+                    compression_threads: options.threads,
+                    block_index: options.block_index,
+                    ..Default::default()
+                },
+            )?;
+            println!("Fractional bits: {}", report.fractional_bits);
+            println!("Max error: {:?}", report.max_error);
+            if !report.clipped_points.is_empty() {
+                println!(
+                    "Warning: {} positions were outside the fixed point range and were clipped",
+                    report.clipped_points.len()
+                );
+            }
+            Ok(())
+        }
+        "ply" => Ok(write_ply(
+            cloud,
             output,
-            options.compressed,
-            options.omit_spherical_harmonics,
-        ),
-        "ply" => write_ply(&gaussians, output, &options.ply_encoding),
+            &PlyWriterOptions {
+                encoding: options.ply_encoding.clone(),
+                max_sh_degree: options.max_sh_degree,
+                double_positions: options.ply_double_positions,
+                compressed: output.to_string_lossy().ends_with(".compressed.ply"),
+            },
+        )?),
         _ => panic!("Unsupported file extension"),
     }
 }
 
 fn diff(old: &Path, new: &Path, limit: Option<usize>) -> Result<()> {
-    let mut old = load(old)?;
-    let mut new = load(new)?;
+    let mut old = load(old)?.to_gaussians();
+    let mut new = load(new)?.to_gaussians();
 
     if let Some(limit) = limit {
         old.truncate(limit);
@@ -294,11 +421,11 @@ fn side_by_side(left: &str, right: &str) -> String {
 }
 
 fn validate(input: &Path) -> Result<()> {
-    let gaussians = load(input)?;
+    let gaussians = load(input)?.to_gaussians();
     for (index, g) in gaussians.iter().enumerate() {
         if !g.is_valid() {
             println!("Invalid gaussian at index {}\n\t{:?}", index, g);
         }
     }
-    return Ok(());
+    Ok(())
 }
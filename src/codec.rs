@@ -0,0 +1,109 @@
+use crate::error::Result;
+use crate::gaussian_cloud::GaussianCloud;
+use crate::spz_format::{write_spz_with_compression, Columns};
+use crate::spz_reader::{SPZReader, SPZReaderOptions};
+use crate::spz_writer::SPZWriterOptions;
+
+/// Options for `encode`.
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// Whether to gzip the output, as `.spz` files are. Defaults to true.
+    pub compressed: bool,
+    pub writer: SPZWriterOptions,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            compressed: true,
+            writer: SPZWriterOptions::default(),
+        }
+    }
+}
+
+/// Encodes a cloud to the bytes of an SPZ file.
+pub fn encode(cloud: &GaussianCloud, options: &EncodeOptions) -> Result<Vec<u8>> {
+    let (bytes, _) = write_spz_with_compression(
+        cloud,
+        Vec::new(),
+        options.compressed,
+        options.writer.clone(),
+    )?;
+    Ok(bytes)
+}
+
+/// Options for `decode`.
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    /// Compression detection and limits. By default compression is detected from the data.
+    pub reader: SPZReaderOptions,
+    /// The columns to decode. Defaults to all of them.
+    pub columns: Columns,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            reader: SPZReaderOptions::default(),
+            columns: Columns::all(),
+        }
+    }
+}
+
+/// Decodes the bytes of an SPZ file, gzip compressed or not.
+pub fn decode(bytes: &[u8], options: &DecodeOptions) -> Result<GaussianCloud> {
+    SPZReader::new_from_slice(bytes, options.reader.clone()).read_columns(options.columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unpacked_gaussian::UnpackedGaussian;
+    use vek::Vec3;
+
+    #[test]
+    fn test_encode_decode() {
+        let gaussians = (0..5)
+            .map(|n| UnpackedGaussian {
+                position: Vec3::new(n as f32 / 4.0 + 0.25, 0.5, -1.0),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+
+        let compressed = encode(&cloud, &EncodeOptions::default()).unwrap();
+        assert_eq!(compressed[..2], [0x1f, 0x8b]);
+        let raw = encode(
+            &cloud,
+            &EncodeOptions {
+                compressed: false,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(raw[..4], *b"NGSP");
+
+        for bytes in [&compressed, &raw] {
+            let result = decode(bytes, &DecodeOptions::default()).unwrap();
+            assert_eq!(result.positions, cloud.positions);
+            assert_eq!(result.len(), 5);
+        }
+
+        let positions = decode(
+            &compressed,
+            &DecodeOptions {
+                columns: Columns::POSITIONS,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(positions.positions, cloud.positions);
+        assert!(positions.alphas.is_empty());
+
+        let options = DecodeOptions {
+            reader: SPZReaderOptions::default().max_points(4),
+            ..Default::default()
+        };
+        assert!(decode(&compressed, &options).is_err());
+    }
+}
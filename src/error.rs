@@ -0,0 +1,181 @@
+use std::fmt;
+use thiserror::Error;
+
+use crate::gaussian_cloud::ShDegreePolicy;
+
+/// The part of an SPZ stream being read when an error occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Header,
+    Positions,
+    Alphas,
+    Colors,
+    Scales,
+    Rotations,
+    SphericalHarmonics,
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Column::Header => "header",
+            Column::Positions => "positions",
+            Column::Alphas => "alphas",
+            Column::Colors => "colors",
+            Column::Scales => "scales",
+            Column::Rotations => "rotations",
+            Column::SphericalHarmonics => "spherical harmonics",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SpzError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    /// The stream ended before a column was complete. `offset` is the position in the
+    /// uncompressed stream where data ran out.
+    #[error("truncated {column}: wanted {expected} bytes at offset {offset}, got {actual}")]
+    Truncated {
+        column: Column,
+        offset: usize,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// Reading (or decompressing) a column failed. `offset` is the position in the uncompressed
+    /// stream where the failing read started.
+    #[error("failed to read {column} at offset {offset}: {source}")]
+    Read {
+        column: Column,
+        offset: usize,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The uncompressed payload continues past the size described by the header.
+    #[error("unexpected data after the {expected} bytes described by the header")]
+    TrailingData { expected: usize },
+
+    /// More gaussians than `SPZReaderOptions::max_points` allows, or than an SPZ header can count.
+    #[error("{num_points} gaussians exceeds the limit of {max}")]
+    TooManyPoints { num_points: usize, max: usize },
+
+    /// The header describes a larger payload than `SPZReaderOptions::max_uncompressed_size` allows.
+    #[error("uncompressed size of {size} bytes exceeds the limit of {max}")]
+    TooLarge { size: usize, max: usize },
+
+    /// Format detection found neither a gzip stream nor raw SPZ. Holds the first bytes read.
+    #[error("neither gzip nor raw SPZ data, starts with {0:02x?}")]
+    UnrecognizedFormat(Vec<u8>),
+
+    #[error("invalid magic number {0:#010x}")]
+    InvalidMagic(u32),
+
+    #[error("unsupported SPZ version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("invalid spherical harmonics degree {0}")]
+    InvalidShDegree(u8),
+
+    #[error("spherical harmonics bit depth {0} is not in 1..=8")]
+    InvalidShBits(u8),
+
+    #[error("fractional bits {0} is not in 0..=23")]
+    InvalidFractionalBits(u8),
+
+    #[error("compression level {0} is not in 0..=9")]
+    InvalidCompressionLevel(u32),
+
+    /// Degree 3, the highest supported, has 15 coefficient vectors.
+    #[error("{0} spherical harmonics coefficient vectors is more than degree 3 allows")]
+    TooManyShCoefficients(usize),
+
+    #[error("all gaussians must have the same spherical harmonics degree, found {0:?}")]
+    ShDegreeMismatch(Vec<u8>),
+
+    /// A batch written by `SPZStreamWriter` has a spherical harmonics degree that `policy` can't
+    /// convert to the degree of the batches already written.
+    #[error("{policy:?} policy cannot convert spherical harmonics degree {actual} to {expected}")]
+    ShDegreeConflict {
+        policy: ShDegreePolicy,
+        expected: u8,
+        actual: u8,
+    },
+
+    #[error("no gaussians")]
+    NoGaussians,
+
+    /// A `GaussianCloud` column does not hold one entry per gaussian.
+    #[error("{column} column has {actual} entries, expected {expected}")]
+    ColumnLength {
+        column: Column,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// A `GaussianCloud` extra attribute does not hold one value per gaussian.
+    #[error("extra attribute \"{name}\" has {actual} values, expected {expected}")]
+    ExtraAttributeLength {
+        name: String,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("not a permutation of all gaussians")]
+    InvalidPermutation,
+
+    #[error("index {index} is out of range for {len} gaussians")]
+    IndexOutOfRange { index: usize, len: usize },
+
+    /// Packed gaussians with different file-wide settings can't be combined without requantizing.
+    #[error("cannot combine packed gaussians with different {0}")]
+    IncompatiblePacked(&'static str),
+
+    #[error("the gzip stream has no block index")]
+    MissingBlockIndex,
+
+    /// The block index doesn't match the compressed blocks or the SPZ header.
+    #[error("invalid block index: {0}")]
+    InvalidBlockIndex(&'static str),
+
+    #[error("the header has not been read")]
+    MissingHeader,
+
+    /// The PLY header or payload couldn't be parsed.
+    #[error("PLY parse error: {0}")]
+    PlyParse(String),
+
+    #[error("unknown PLY element \"{0}\"")]
+    UnknownPlyElement(String),
+
+    #[error("missing PLY element \"{0}\"")]
+    MissingPlyElement(String),
+
+    /// A property of a type that can't be read, like a list where a scalar is expected.
+    #[error("unsupported PLY {element} property \"{name}\"")]
+    UnsupportedPlyProperty { element: String, name: String },
+
+    #[error("missing PLY {element} property \"{name}\"")]
+    MissingPlyProperty { element: String, name: String },
+
+    /// The `f_rest` properties must be `f_rest_0` to `f_rest_8`, `f_rest_23` or `f_rest_44`.
+    #[error("expected 0, 9, 24 or 45 f_rest properties, found {count} up to f_rest_{max_index}")]
+    InvalidShPropertyCount { count: usize, max_index: usize },
+
+    /// Elements of a compressed PLY file don't have the counts the vertex element requires.
+    #[error("expected {expected} PLY {element} elements, found {actual}")]
+    PlyElementCount {
+        element: String,
+        expected: usize,
+        actual: usize,
+    },
+
+    /// An extra attribute has the name of a gaussian property or of another extra attribute.
+    #[error("extra attribute \"{0}\" clashes with another PLY property")]
+    ExtraAttributeClash(String),
+}
+
+pub type Result<T, E = SpzError> = std::result::Result<T, E>;
@@ -7,8 +7,9 @@ impl FixedPoint24 {
     }
 
     pub(crate) fn into(self, fractional_bits: usize) -> [u8; 3] {
-        // 1) Multiply float by 2^fractional_bits.
-        let scaling_factor = (1 << fractional_bits) as f32;
+        // 1) Multiply float by 2^fractional_bits. Computed in floating point, since an untrusted
+        //    header can hold any value up to 255.
+        let scaling_factor = 2f32.powi(fractional_bits as i32);
         // 2) Convert to integer (rounding or truncating as desired).
         let scaled = (self.0 * scaling_factor).round();
 
@@ -28,6 +29,13 @@ impl FixedPoint24 {
         ]
     }
 
+    /// Whether the value is outside the range `into` can store with `fractional_bits`, and would
+    /// be clamped.
+    pub(crate) fn is_clipped(&self, fractional_bits: usize) -> bool {
+        let scaled = (self.0 * 2f32.powi(fractional_bits as i32)).round();
+        !(-8_388_608.0..=8_388_607.0).contains(&scaled)
+    }
+
     pub(crate) fn from(bytes: [u8; 3], fractional_bits: usize) -> Self {
         // 1) Reconstruct the 24-bit unsigned integer from the little-endian byte order.
         let raw = (bytes[0] as u32) | ((bytes[1] as u32) << 8) | ((bytes[2] as u32) << 16);
@@ -37,7 +45,7 @@ impl FixedPoint24 {
         let extended = ((raw << 8) as i32) >> 8;
 
         // 3) Convert the integer back to float by dividing by 2^fractional_bits.
-        let scaling_factor = (1 << fractional_bits) as f32;
+        let scaling_factor = 2f32.powi(fractional_bits as i32);
         let value = extended as f32 / scaling_factor;
 
         Self(value)
@@ -48,21 +56,23 @@ impl FixedPoint24 {
         if value == 0.0 {
             return 0;
         }
-        let value = value.abs().ceil();
-        let bits = value.log2().ceil() as usize;
-        24 - bits - 1
+        // A value in [2^(n-1), 2^n) needs n integer bits next to the sign bit. Values too large for
+        // 24 bits get no fractional bits and are clamped when packed.
+        let value = value.abs();
+        let integer_bits = value.log2().floor() as i64 + 1;
+        let mut bits = (23 - integer_bits).clamp(0, 23) as usize;
+        // Rounding can still carry a value just below a power of two out of range.
+        while bits > 0 && Self(value).is_clipped(bits) {
+            bits -= 1;
+        }
+        bits
     }
 }
 
 // Given an array of floats and the desired bit_count work out the ideal number of fractional bits needed to represent the floats with as much precision as possible.
 pub(crate) fn compute_fixed_point_fractional_bits(floats: &[f32], bit_count: usize) -> usize {
     assert!(bit_count == 24);
-    let max = floats
-        .iter()
-        .map(|v| v.abs().ceil())
-        .max_by(|a, b| a.partial_cmp(b).unwrap())
-        .unwrap_or(0.0);
-    println!("Max: {}", max);
+    let max = floats.iter().map(|v| v.abs()).fold(0.0, f32::max);
     FixedPoint24::new(max).optimal_fractional_bits()
 }
 
@@ -74,13 +84,23 @@ mod tests {
     #[test]
     fn test_fraction_bits() {
         assert_eq!(FixedPoint24::new(0.0).optimal_fractional_bits(), 0);
-        assert_eq!(FixedPoint24::new(1.0).optimal_fractional_bits(), 23);
+        assert_eq!(FixedPoint24::new(0.25).optimal_fractional_bits(), 23);
+        assert_eq!(FixedPoint24::new(0.5).optimal_fractional_bits(), 23);
+        assert_eq!(FixedPoint24::new(0.99999994).optimal_fractional_bits(), 22);
+        assert_eq!(FixedPoint24::new(1.0).optimal_fractional_bits(), 22);
         assert_eq!(FixedPoint24::new(1.5).optimal_fractional_bits(), 22);
-        assert_eq!(FixedPoint24::new(-1.0).optimal_fractional_bits(), 23);
+        assert_eq!(FixedPoint24::new(-1.0).optimal_fractional_bits(), 22);
         assert_eq!(FixedPoint24::new(-1.5).optimal_fractional_bits(), 22);
+        assert_eq!(FixedPoint24::new(2.0).optimal_fractional_bits(), 21);
+        assert_eq!(FixedPoint24::new(64.0).optimal_fractional_bits(), 16);
         assert_eq!(FixedPoint24::new(100.0).optimal_fractional_bits(), 16);
         assert_eq!(FixedPoint24::new(100.5).optimal_fractional_bits(), 16);
         assert_eq!(FixedPoint24::new(-105.12345).optimal_fractional_bits(), 16);
+        assert_eq!(FixedPoint24::new(1.0e9).optimal_fractional_bits(), 0);
+        for exponent in -4..23 {
+            let value = FixedPoint24::new(2f32.powi(exponent));
+            assert!(!value.is_clipped(value.optimal_fractional_bits()));
+        }
     }
 
     #[test]
@@ -195,5 +215,10 @@ mod tests {
         assert_relative_eq!(result[0], floats[0], epsilon = 1e-3);
         assert_relative_eq!(result[1], floats[1], epsilon = 1e-3);
         assert_relative_eq!(result[2], floats[2], epsilon = 1e-3);
+
+        assert_eq!(
+            compute_fixed_point_fractional_bits(&[f32::NAN, -2.0], 24),
+            21
+        );
     }
 }
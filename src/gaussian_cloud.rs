@@ -0,0 +1,617 @@
+pub use ply_rs::ply::ScalarType;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use vek::{Quaternion, Vec3};
+
+use crate::error::{Column, Result, SpzError};
+use crate::nearest_neighbours::mean_squared_distances;
+use crate::spherical_harmonics::{SphericalHarmonics, SphericalHarmonicsOrder};
+use crate::support::{inv_sigmoid, linear_to_sph0};
+use crate::unpacked_gaussian::UnpackedGaussian;
+
+/// How to reconcile gaussians with different spherical harmonics degrees when combining them into
+/// one cloud, for example when building a cloud from individual gaussians or merging clouds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ShDegreePolicy {
+    /// Fail with `SpzError::ShDegreeMismatch`.
+    #[default]
+    Strict,
+    /// Zero-pad every gaussian to the highest degree.
+    Promote,
+    /// Truncate every gaussian to the lowest degree.
+    Truncate,
+}
+
+impl ShDegreePolicy {
+    /// The degree all of `degrees` should be converted to. Zero if there are none.
+    pub fn resolve(self, degrees: &BTreeSet<u8>) -> Result<u8> {
+        match self {
+            ShDegreePolicy::Strict if degrees.len() > 1 => Err(SpzError::ShDegreeMismatch(
+                degrees.iter().copied().collect(),
+            )),
+            ShDegreePolicy::Strict | ShDegreePolicy::Truncate => {
+                Ok(degrees.first().copied().unwrap_or(0))
+            }
+            ShDegreePolicy::Promote => Ok(degrees.last().copied().unwrap_or(0)),
+        }
+    }
+}
+
+/// The opacity of the gaussians built by `GaussianCloud::from_points`.
+const POINT_OPACITY: f32 = 0.9;
+
+/// A per-gaussian scalar that isn't part of the gaussian model, like the `semantic_id` or
+/// `confidence` properties some trainers add to their PLY files. Extra attributes are read from
+/// and written to PLY files, but SPZ has no place for them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtraAttribute {
+    pub name: String,
+    /// The PLY type the values were read as, and are written as.
+    pub data_type: ScalarType,
+    /// One value per gaussian. Every PLY scalar type converts to `f64` exactly.
+    pub values: Vec<f64>,
+}
+
+impl ExtraAttribute {
+    /// An attribute with the same name and type, but no values.
+    fn clone_empty(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            data_type: self.data_type.clone(),
+            values: Vec::new(),
+        }
+    }
+}
+
+/// A cloud of gaussians stored column by column, mirroring the layout of an SPZ file.
+///
+/// Every column holds `num_points` entries, except `spherical_harmonics` which holds
+/// `sh_scalar_count()` scalars per gaussian. All gaussians share the cloud's `sh_degree`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GaussianCloud {
+    pub num_points: usize,
+    pub sh_degree: u8,
+    /// Whether the cloud was trained with antialiasing. See `SPZFlags::ANTIALIASED`.
+    pub antialiased: bool,
+    pub positions: Vec<Vec3<f32>>,
+    pub rotations: Vec<Quaternion<f32>>,
+    pub scales: Vec<Vec3<f32>>,
+    /// The colors of the gaussians, as degree 0 spherical harmonics coefficients (`f_dc` in PLY
+    /// files). `0.5 + 0.2821 * c` is the linear color.
+    pub colors: Vec<Vec3<f32>>,
+    /// The opacities of the gaussians, as logits. `sigmoid(a)` is the linear opacity.
+    pub alphas: Vec<f32>,
+    /// The spherical harmonics coefficients of all gaussians, in the same order as
+    /// `SphericalHarmonics::scalars` (coefficient-major, RGB interleaved).
+    pub spherical_harmonics: Vec<f32>,
+    /// Additional named columns, each with one value per gaussian.
+    pub extra_attributes: Vec<ExtraAttribute>,
+}
+
+impl GaussianCloud {
+    /// Builds a cloud from individual gaussians. All gaussians must have the same spherical
+    /// harmonics degree.
+    pub fn from_gaussians(gaussians: &[UnpackedGaussian]) -> Result<Self> {
+        Self::from_gaussians_with_policy(gaussians, ShDegreePolicy::Strict)
+    }
+
+    /// Builds a cloud from individual gaussians, reconciling their spherical harmonics degrees
+    /// with `policy`.
+    pub fn from_gaussians_with_policy(
+        gaussians: &[UnpackedGaussian],
+        policy: ShDegreePolicy,
+    ) -> Result<Self> {
+        let sh_degrees = gaussians
+            .iter()
+            .map(|g| g.spherical_harmonics.order().index() as u8)
+            .collect::<BTreeSet<_>>();
+        let sh_degree = policy.resolve(&sh_degrees)?;
+
+        let mut cloud = GaussianCloud {
+            sh_degree,
+            ..Default::default()
+        };
+        for gaussian in gaussians {
+            cloud.push(gaussian);
+        }
+        Ok(cloud)
+    }
+
+    /// Builds a cloud from a plain point cloud, like a photogrammetry or LiDAR scan, with colors
+    /// in `0.0..=1.0`. Each point becomes a mostly opaque, isotropic gaussian without view
+    /// dependent color, sized by the root mean square distance to its three nearest neighbours,
+    /// as 3DGS initializes training.
+    pub fn from_points(positions: &[Vec3<f32>], colors: &[Vec3<f32>]) -> Result<Self> {
+        if colors.len() != positions.len() {
+            return Err(SpzError::ColumnLength {
+                column: Column::Colors,
+                expected: positions.len(),
+                actual: colors.len(),
+            });
+        }
+        let scales = mean_squared_distances(positions, 3)
+            .into_iter()
+            // A lone point gets unit scale. Coincident points are kept from collapsing.
+            .map(|distance| Vec3::broadcast(0.5 * distance.map_or(0.0, |d| d.max(1e-7).ln())))
+            .collect();
+        Ok(GaussianCloud {
+            num_points: positions.len(),
+            positions: positions.to_vec(),
+            rotations: vec![Quaternion::identity(); positions.len()],
+            scales,
+            colors: colors.iter().map(|c| c.map(linear_to_sph0)).collect(),
+            alphas: vec![inv_sigmoid(POINT_OPACITY); positions.len()],
+            ..Default::default()
+        })
+    }
+
+    /// Converts the cloud back into individual gaussians. An invalid cloud has none.
+    pub fn to_gaussians(&self) -> Vec<UnpackedGaussian> {
+        (0..self.num_points)
+            .map_while(|i| self.gaussian(i).ok())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_points == 0
+    }
+
+    pub fn sh_order(&self) -> Result<SphericalHarmonicsOrder> {
+        SphericalHarmonicsOrder::order_for_degree(self.sh_degree)
+            .ok_or(SpzError::InvalidShDegree(self.sh_degree))
+    }
+
+    /// The number of spherical harmonics scalars stored per gaussian.
+    pub fn sh_scalar_count(&self) -> usize {
+        SphericalHarmonicsOrder::order_for_degree(self.sh_degree)
+            .map_or(0, |order| order.scalar_count())
+    }
+
+    /// The spherical harmonics scalars of the gaussian at `index`, or `None` if it is out of range.
+    pub fn sh_scalars(&self, index: usize) -> Option<&[f32]> {
+        if index >= self.num_points {
+            return None;
+        }
+        let count = self.sh_scalar_count();
+        self.spherical_harmonics
+            .get(index * count..(index + 1) * count)
+    }
+
+    /// The gaussian at `index`.
+    pub fn gaussian(&self, index: usize) -> Result<UnpackedGaussian> {
+        if index >= self.num_points {
+            return Err(SpzError::IndexOutOfRange {
+                index,
+                len: self.num_points,
+            });
+        }
+        self.validate()?;
+        let mut spherical_harmonics = SphericalHarmonics::default();
+        // `validate` checked the column length.
+        spherical_harmonics.set_scalars(self.sh_scalars(index).unwrap_or_default())?;
+        Ok(UnpackedGaussian {
+            position: self.positions[index],
+            rotation: self.rotations[index],
+            scales: self.scales[index],
+            color: self.colors[index],
+            alpha: self.alphas[index],
+            spherical_harmonics,
+        })
+    }
+
+    /// The extra attribute called `name`, if there is one.
+    pub fn extra_attribute(&self, name: &str) -> Option<&ExtraAttribute> {
+        self.extra_attributes.iter().find(|a| a.name == name)
+    }
+
+    /// Appends a gaussian. Its spherical harmonics are padded or truncated to the cloud's degree,
+    /// and its extra attributes are zero.
+    pub fn push(&mut self, gaussian: &UnpackedGaussian) {
+        self.positions.push(gaussian.position);
+        self.rotations.push(gaussian.rotation);
+        self.scales.push(gaussian.scales);
+        self.colors.push(gaussian.color);
+        self.alphas.push(gaussian.alpha);
+        let mut scalars = gaussian.spherical_harmonics.scalars();
+        scalars.resize(self.sh_scalar_count(), 0.0);
+        self.spherical_harmonics.extend(scalars);
+        for attribute in &mut self.extra_attributes {
+            attribute.values.push(0.0);
+        }
+        self.num_points += 1;
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        let len = len.min(self.num_points);
+        self.positions.truncate(len);
+        self.rotations.truncate(len);
+        self.scales.truncate(len);
+        self.colors.truncate(len);
+        self.alphas.truncate(len);
+        self.spherical_harmonics
+            .truncate(len * self.sh_scalar_count());
+        for attribute in &mut self.extra_attributes {
+            attribute.values.truncate(len);
+        }
+        self.num_points = len;
+    }
+
+    /// Reorders the gaussians. `order` must hold each index exactly once.
+    pub fn permute(&self, order: &[usize]) -> Result<GaussianCloud> {
+        let mut seen = vec![false; self.num_points];
+        for &index in order {
+            match seen.get_mut(index) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(SpzError::InvalidPermutation),
+            }
+        }
+        if order.len() != self.num_points {
+            return Err(SpzError::InvalidPermutation);
+        }
+        self.select(order)
+    }
+
+    /// Returns the gaussians at `indices`, in that order.
+    pub fn subset(&self, indices: &[usize]) -> Result<GaussianCloud> {
+        if let Some(&index) = indices.iter().find(|&&index| index >= self.num_points) {
+            return Err(SpzError::IndexOutOfRange {
+                index,
+                len: self.num_points,
+            });
+        }
+        self.select(indices)
+    }
+
+    fn select(&self, indices: &[usize]) -> Result<GaussianCloud> {
+        self.validate()?;
+        let sh_count = self.sh_scalar_count();
+        Ok(GaussianCloud {
+            num_points: indices.len(),
+            sh_degree: self.sh_degree,
+            antialiased: self.antialiased,
+            positions: indices.iter().map(|&i| self.positions[i]).collect(),
+            rotations: indices.iter().map(|&i| self.rotations[i]).collect(),
+            scales: indices.iter().map(|&i| self.scales[i]).collect(),
+            colors: indices.iter().map(|&i| self.colors[i]).collect(),
+            alphas: indices.iter().map(|&i| self.alphas[i]).collect(),
+            spherical_harmonics: indices
+                .iter()
+                .flat_map(|&i| &self.spherical_harmonics[i * sh_count..(i + 1) * sh_count])
+                .copied()
+                .collect(),
+            extra_attributes: self
+                .extra_attributes
+                .iter()
+                .map(|attribute| ExtraAttribute {
+                    values: indices.iter().map(|&i| attribute.values[i]).collect(),
+                    ..attribute.clone_empty()
+                })
+                .collect(),
+        })
+    }
+
+    /// Changes the spherical harmonics degree of every gaussian, truncating coefficients or padding
+    /// them with zeros.
+    pub fn set_sh_degree(&mut self, sh_degree: u8) -> Result<()> {
+        let order = SphericalHarmonicsOrder::order_for_degree(sh_degree)
+            .ok_or(SpzError::InvalidShDegree(sh_degree))?;
+        self.validate()?;
+        let mut spherical_harmonics = Vec::with_capacity(self.num_points * order.scalar_count());
+        for i in 0..self.num_points {
+            let mut sh = SphericalHarmonics::default();
+            sh.set_scalars(self.sh_scalars(i).unwrap_or_default())?;
+            sh.reorder(order);
+            spherical_harmonics.extend(sh.scalars());
+        }
+        self.spherical_harmonics = spherical_harmonics;
+        self.sh_degree = sh_degree;
+        Ok(())
+    }
+
+    /// Returns the cloud with its spherical harmonics converted to `sh_degree`, borrowing it
+    /// unchanged if it already has that degree.
+    pub fn with_sh_degree(&self, sh_degree: u8) -> Result<Cow<'_, GaussianCloud>> {
+        if sh_degree > 3 {
+            return Err(SpzError::InvalidShDegree(sh_degree));
+        }
+        if self.sh_degree == sh_degree {
+            return Ok(Cow::Borrowed(self));
+        }
+        let mut cloud = self.clone();
+        cloud.set_sh_degree(sh_degree)?;
+        Ok(Cow::Owned(cloud))
+    }
+
+    /// Returns the cloud with its spherical harmonics truncated to at most `max_sh_degree`,
+    /// borrowing it unchanged if its degree is already low enough.
+    pub fn with_max_sh_degree(&self, max_sh_degree: u8) -> Result<Cow<'_, GaussianCloud>> {
+        if max_sh_degree > 3 {
+            return Err(SpzError::InvalidShDegree(max_sh_degree));
+        }
+        self.with_sh_degree(self.sh_degree.min(max_sh_degree))
+    }
+
+    /// Merges clouds into one, in order, reconciling their spherical harmonics degrees with
+    /// `policy`. Empty inputs are ignored when deciding the degree, whether the result is
+    /// antialiased and which extra attributes it keeps.
+    pub fn concatenate(clouds: &[GaussianCloud], policy: ShDegreePolicy) -> Result<Self> {
+        let mut non_empty = clouds.iter().filter(|cloud| !cloud.is_empty());
+        let sh_degrees = non_empty
+            .clone()
+            .map(|cloud| cloud.sh_degree)
+            .collect::<BTreeSet<_>>();
+        let mut result = GaussianCloud {
+            sh_degree: policy.resolve(&sh_degrees)?,
+            antialiased: non_empty.clone().next().is_some()
+                && non_empty.clone().all(|cloud| cloud.antialiased),
+            ..Default::default()
+        };
+        if let Some(first) = non_empty.next() {
+            result.extra_attributes = first
+                .extra_attributes
+                .iter()
+                .filter(|attribute| {
+                    non_empty
+                        .clone()
+                        .all(|cloud| cloud.extra_attribute(&attribute.name).is_some())
+                })
+                .map(ExtraAttribute::clone_empty)
+                .collect();
+        }
+        for cloud in clouds {
+            cloud.validate()?;
+            let cloud = cloud.with_sh_degree(result.sh_degree)?;
+            result.positions.extend_from_slice(&cloud.positions);
+            result.rotations.extend_from_slice(&cloud.rotations);
+            result.scales.extend_from_slice(&cloud.scales);
+            result.colors.extend_from_slice(&cloud.colors);
+            result.alphas.extend_from_slice(&cloud.alphas);
+            result
+                .spherical_harmonics
+                .extend_from_slice(&cloud.spherical_harmonics);
+            for attribute in &mut result.extra_attributes {
+                if let Some(values) = cloud.extra_attribute(&attribute.name).map(|a| &a.values) {
+                    attribute.values.extend_from_slice(values);
+                }
+            }
+            result.num_points += cloud.num_points;
+        }
+        Ok(result)
+    }
+
+    /// Checks that every column holds the right number of entries.
+    pub fn validate(&self) -> Result<()> {
+        let sh_count = self.sh_order()?.scalar_count();
+        let columns = [
+            (Column::Positions, self.positions.len(), self.num_points),
+            (Column::Alphas, self.alphas.len(), self.num_points),
+            (Column::Colors, self.colors.len(), self.num_points),
+            (Column::Scales, self.scales.len(), self.num_points),
+            (Column::Rotations, self.rotations.len(), self.num_points),
+            (
+                Column::SphericalHarmonics,
+                self.spherical_harmonics.len(),
+                self.num_points * sh_count,
+            ),
+        ];
+        for (column, actual, expected) in columns {
+            if actual != expected {
+                return Err(SpzError::ColumnLength {
+                    column,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        for attribute in &self.extra_attributes {
+            if attribute.values.len() != self.num_points {
+                return Err(SpzError::ExtraAttributeLength {
+                    name: attribute.name.clone(),
+                    expected: self.num_points,
+                    actual: attribute.values.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&[UnpackedGaussian]> for GaussianCloud {
+    type Error = SpzError;
+
+    fn try_from(gaussians: &[UnpackedGaussian]) -> Result<Self> {
+        GaussianCloud::from_gaussians(gaussians)
+    }
+}
+
+impl From<&GaussianCloud> for Vec<UnpackedGaussian> {
+    fn from(cloud: &GaussianCloud) -> Self {
+        cloud.to_gaussians()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gaussian(n: f32) -> UnpackedGaussian {
+        UnpackedGaussian {
+            position: Vec3::new(n, n + 1.0, n + 2.0),
+            rotation: Quaternion::from_xyzw(0.0, 0.0, 0.0, 1.0),
+            scales: Vec3::broadcast(n),
+            color: Vec3::broadcast(n / 10.0),
+            alpha: n,
+            spherical_harmonics: SphericalHarmonics::Order1([
+                Vec3::broadcast(n),
+                Vec3::broadcast(-n),
+                Vec3::broadcast(n * 2.0),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let gaussians = (0..4).map(|n| gaussian(n as f32)).collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+        assert_eq!(cloud.len(), 4);
+        assert_eq!(cloud.sh_degree, 1);
+        assert_eq!(cloud.spherical_harmonics.len(), 4 * 9);
+        assert!(cloud.validate().is_ok());
+        assert_eq!(cloud.to_gaussians(), gaussians);
+
+        let subset = cloud.subset(&[3, 1]).unwrap();
+        assert_eq!(subset.to_gaussians(), vec![gaussians[3], gaussians[1]]);
+        let permuted = cloud.permute(&[3, 2, 1, 0]).unwrap();
+        assert_eq!(permuted.gaussian(0).unwrap(), gaussians[3]);
+        assert!(matches!(
+            cloud.permute(&[3, 1]),
+            Err(SpzError::InvalidPermutation)
+        ));
+        assert!(matches!(
+            cloud.permute(&[0, 0, 1, 2]),
+            Err(SpzError::InvalidPermutation)
+        ));
+        assert!(matches!(
+            cloud.subset(&[4]),
+            Err(SpzError::IndexOutOfRange { index: 4, len: 4 })
+        ));
+        assert_eq!(cloud.sh_scalars(4), None);
+        assert!(matches!(
+            cloud.gaussian(4),
+            Err(SpzError::IndexOutOfRange { index: 4, len: 4 })
+        ));
+
+        let mut truncated = cloud.clone();
+        truncated.truncate(2);
+        assert!(truncated.validate().is_ok());
+        assert_eq!(truncated.to_gaussians(), gaussians[..2]);
+    }
+
+    #[test]
+    fn test_sh_degree_policy() {
+        let gaussians = vec![gaussian(1.0), UnpackedGaussian::default()];
+
+        let promoted =
+            GaussianCloud::from_gaussians_with_policy(&gaussians, ShDegreePolicy::Promote).unwrap();
+        assert_eq!(promoted.sh_degree, 1);
+        assert!(promoted.validate().is_ok());
+        assert_eq!(
+            promoted.sh_scalars(0).unwrap(),
+            gaussians[0].spherical_harmonics.scalars()
+        );
+        assert_eq!(promoted.sh_scalars(1).unwrap(), [0.0; 9]);
+
+        let truncated =
+            GaussianCloud::from_gaussians_with_policy(&gaussians, ShDegreePolicy::Truncate)
+                .unwrap();
+        assert_eq!(truncated.sh_degree, 0);
+        assert!(truncated.validate().is_ok());
+
+        let first = GaussianCloud::from_gaussians(&gaussians[..1]).unwrap();
+        let second = GaussianCloud::from_gaussians(&gaussians[1..]).unwrap();
+        let clouds = [first, second];
+        assert_eq!(
+            GaussianCloud::concatenate(&clouds, ShDegreePolicy::Promote).unwrap(),
+            promoted
+        );
+        assert_eq!(
+            GaussianCloud::concatenate(&clouds, ShDegreePolicy::Truncate).unwrap(),
+            truncated
+        );
+        assert!(matches!(
+            GaussianCloud::concatenate(&clouds, ShDegreePolicy::Strict),
+            Err(SpzError::ShDegreeMismatch(degrees)) if degrees == vec![0, 1]
+        ));
+    }
+
+    #[test]
+    fn test_set_sh_degree() {
+        let gaussians = (0..2).map(|n| gaussian(n as f32 + 1.0)).collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+        assert!(matches!(
+            cloud.with_max_sh_degree(1).unwrap(),
+            Cow::Borrowed(_)
+        ));
+
+        let mut promoted = cloud.clone();
+        promoted.set_sh_degree(2).unwrap();
+        assert!(promoted.validate().is_ok());
+        assert_eq!(
+            promoted.sh_scalars(1).unwrap()[..9],
+            *cloud.sh_scalars(1).unwrap()
+        );
+        assert!(promoted.sh_scalars(1).unwrap()[9..]
+            .iter()
+            .all(|&v| v == 0.0));
+
+        let truncated = promoted.with_max_sh_degree(0).unwrap();
+        assert_eq!(truncated.sh_degree, 0);
+        assert!(truncated.spherical_harmonics.is_empty());
+        assert!(truncated.validate().is_ok());
+        assert!(matches!(
+            cloud.with_max_sh_degree(4),
+            Err(SpzError::InvalidShDegree(4))
+        ));
+    }
+
+    #[test]
+    fn test_extra_attributes() {
+        let attribute = |name: &str, values: Vec<f64>| ExtraAttribute {
+            name: name.to_string(),
+            data_type: ScalarType::Float,
+            values,
+        };
+        let mut first = GaussianCloud::from_gaussians(&[gaussian(1.0)]).unwrap();
+        first.extra_attributes = vec![attribute("a", vec![1.0]), attribute("b", vec![2.0])];
+        first.push(&gaussian(2.0));
+        assert!(first.validate().is_ok());
+        assert_eq!(first.extra_attribute("b").unwrap().values, [2.0, 0.0]);
+
+        let mut second = GaussianCloud::from_gaussians(&[gaussian(3.0)]).unwrap();
+        second.extra_attributes = vec![attribute("b", vec![3.0])];
+        let merged =
+            GaussianCloud::concatenate(&[first.clone(), second], ShDegreePolicy::Strict).unwrap();
+        assert_eq!(
+            merged.extra_attributes,
+            [attribute("b", vec![2.0, 0.0, 3.0])]
+        );
+
+        let antialiased = GaussianCloud {
+            antialiased: true,
+            ..merged.clone()
+        };
+        let clouds = [antialiased.clone(), GaussianCloud::default()];
+        let merged = GaussianCloud::concatenate(&clouds, ShDegreePolicy::Strict).unwrap();
+        assert!(merged.antialiased);
+        assert!(
+            !GaussianCloud::concatenate(&[], ShDegreePolicy::Strict)
+                .unwrap()
+                .antialiased
+        );
+
+        first.truncate(1);
+        assert_eq!(first.extra_attribute("a").unwrap().values, [1.0]);
+        first.extra_attributes[1].values.clear();
+        assert!(matches!(
+            first.validate(),
+            Err(SpzError::ExtraAttributeLength {
+                expected: 1,
+                actual: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_mixed_degrees() {
+        let gaussians = vec![gaussian(1.0), UnpackedGaussian::default()];
+        assert!(matches!(
+            GaussianCloud::from_gaussians(&gaussians),
+            Err(SpzError::ShDegreeMismatch(degrees)) if degrees == vec![0, 1]
+        ));
+    }
+}
@@ -0,0 +1,426 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::num::NonZeroUsize;
+
+use flate2::write::GzEncoder;
+use flate2::{Compression, GzBuilder};
+
+/// The amount of uncompressed data in each gzip member written by `ParallelGzEncoder`.
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 22;
+
+/// A gzip encoder that compresses fixed size blocks on several threads.
+///
+/// Each block becomes its own gzip member, so the output is a standard multi-member gzip stream
+/// that any gzip reader (including `SPZReader`) decompresses to the original data. Compression is
+/// slightly worse than a single member, since blocks don't share history.
+///
+/// Like `flate2::write::GzEncoder`, dropping the encoder finishes the stream, ignoring errors. Call
+/// `finish` or `try_finish` to handle them.
+pub struct ParallelGzEncoder<W: Write> {
+    /// Only `None` once `finish` has returned it.
+    writer: Option<W>,
+    level: Compression,
+    threads: usize,
+    block_size: usize,
+    /// Full blocks waiting to be compressed.
+    blocks: Vec<Vec<u8>>,
+    current: Vec<u8>,
+    members_written: usize,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    /// Creates an encoder using `threads` threads, or all available cores if `threads` is 0.
+    pub fn new(writer: W, level: Compression, threads: usize) -> Self {
+        Self::with_block_size(writer, level, threads, DEFAULT_BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(
+        writer: W,
+        level: Compression,
+        threads: usize,
+        block_size: usize,
+    ) -> Self {
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            threads => threads,
+        };
+        Self {
+            writer: Some(writer),
+            level,
+            threads,
+            block_size: block_size.max(1),
+            blocks: Vec::new(),
+            current: Vec::new(),
+            members_written: 0,
+        }
+    }
+
+    /// Compresses any remaining data and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self
+            .writer
+            .take()
+            .expect("the writer is only taken by finish"))
+    }
+
+    /// Compresses any remaining data, without giving up the underlying writer. Calling it again
+    /// does nothing, unless more data has been written since.
+    pub fn try_finish(&mut self) -> io::Result<()> {
+        // An empty stream still needs one member to be valid gzip.
+        if !self.current.is_empty() || self.members_written + self.blocks.len() == 0 {
+            self.blocks.push(std::mem::take(&mut self.current));
+        }
+        self.compress_blocks()?;
+        self.writer().flush()
+    }
+
+    fn writer(&mut self) -> &mut W {
+        self.writer
+            .as_mut()
+            .expect("the writer is only taken by finish")
+    }
+
+    fn compress_blocks(&mut self) -> io::Result<()> {
+        let members = compress_members(&self.blocks, self.level, self.threads)?;
+        for member in members {
+            self.writer().write_all(&member)?;
+        }
+        self.members_written += self.blocks.len();
+        self.blocks.clear();
+        Ok(())
+    }
+}
+
+/// The gzip header fields written by this crate: no file name or comment, a zero modification
+/// time and an unknown operating system, so identical input always gives identical bytes.
+pub(crate) fn deterministic_header() -> GzBuilder {
+    GzBuilder::new().mtime(0).operating_system(255)
+}
+
+/// A single member gzip encoder with a `deterministic_header`.
+pub(crate) fn encoder<W: Write>(writer: W, level: Compression) -> GzEncoder<W> {
+    deterministic_header().write(writer, level)
+}
+
+fn compress_member(block: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = encoder(Vec::with_capacity(block.len() / 2), level);
+    encoder.write_all(block)?;
+    encoder.finish()
+}
+
+/// Compresses each block as its own gzip member, `threads` blocks at a time.
+fn compress_members<B: AsRef<[u8]> + Sync>(
+    blocks: &[B],
+    level: Compression,
+    threads: usize,
+) -> io::Result<Vec<Vec<u8>>> {
+    parallel_map(blocks, threads, |block| {
+        compress_member(block.as_ref(), level)
+    })
+    .into_iter()
+    .collect()
+}
+
+/// Runs `f` on each item, `threads` items at a time, keeping the results in order.
+pub(crate) fn parallel_map<T: Sync, U: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> U + Sync,
+) -> Vec<U> {
+    let mut results = Vec::with_capacity(items.len());
+    for group in items.chunks(threads.max(1)) {
+        std::thread::scope(|scope| {
+            let f = &f;
+            let handles = group
+                .iter()
+                .map(|item| scope.spawn(move || f(item)))
+                .collect::<Vec<_>>();
+            for handle in handles {
+                results.push(handle.join().expect("worker thread panicked"));
+            }
+        });
+    }
+    results
+}
+
+/// The gzip extra field subfield id of a block index.
+const BLOCK_INDEX_ID: [u8; 2] = *b"SZ";
+
+/// The largest extra field gzip allows, less the subfield header.
+const MAX_BLOCK_INDEX_LEN: usize = u16::MAX as usize - 4;
+
+/// The compressed and uncompressed size of one gzip member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub compressed_len: u32,
+    pub uncompressed_len: u32,
+}
+
+/// Where each member of a multi-member gzip stream starts, so members can be found and
+/// decompressed independently.
+///
+/// The index is stored in the extra field of the first member, as subfield `SZ`: a little-endian
+/// `u32` member count followed by each member's compressed and uncompressed size as `u32`s. Readers
+/// that don't know the subfield ignore it, so the stream stays ordinary gzip.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockIndex {
+    pub blocks: Vec<Block>,
+}
+
+impl BlockIndex {
+    /// The most members an index can describe, limited by the size of the gzip extra field.
+    pub const MAX_BLOCKS: usize = (MAX_BLOCK_INDEX_LEN - 4) / 8;
+
+    /// The offset of each member in the compressed stream, and of its data in the uncompressed
+    /// stream.
+    pub fn offsets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.blocks.iter().scan((0u64, 0u64), |offsets, block| {
+            let current = *offsets;
+            offsets.0 += block.compressed_len as u64;
+            offsets.1 += block.uncompressed_len as u64;
+            Some(current)
+        })
+    }
+
+    pub fn uncompressed_len(&self) -> u64 {
+        self.blocks.iter().map(|b| b.uncompressed_len as u64).sum()
+    }
+
+    fn to_extra(&self) -> Vec<u8> {
+        let len = 4 + 8 * self.blocks.len();
+        let mut extra = Vec::with_capacity(4 + len);
+        extra.extend_from_slice(&BLOCK_INDEX_ID);
+        extra.extend_from_slice(&(len as u16).to_le_bytes());
+        extra.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        for block in &self.blocks {
+            extra.extend_from_slice(&block.compressed_len.to_le_bytes());
+            extra.extend_from_slice(&block.uncompressed_len.to_le_bytes());
+        }
+        extra
+    }
+
+    /// Reads the index from the header of the gzip member at the start of `reader`. Returns
+    /// `None` if the member has no index.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut header = [0; 10];
+        reader.read_exact(&mut header)?;
+        if header[..3] != [0x1f, 0x8b, 8] {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a gzip stream"));
+        }
+        const FEXTRA: u8 = 0x04;
+        if header[3] & FEXTRA == 0 {
+            return Ok(None);
+        }
+        let mut xlen = [0; 2];
+        reader.read_exact(&mut xlen)?;
+        let mut extra = vec![0; u16::from_le_bytes(xlen) as usize];
+        reader.read_exact(&mut extra)?;
+
+        let mut subfields = extra.as_slice();
+        while subfields.len() >= 4 {
+            let len = u16::from_le_bytes([subfields[2], subfields[3]]) as usize;
+            let data = subfields.get(4..4 + len).ok_or_else(invalid_index)?;
+            if subfields[..2] == BLOCK_INDEX_ID {
+                return Self::from_subfield(data).map(Some);
+            }
+            subfields = &subfields[4 + len..];
+        }
+        Ok(None)
+    }
+
+    fn from_subfield(data: &[u8]) -> io::Result<Self> {
+        let count = data
+            .get(..4)
+            .map(|count| u32::from_le_bytes(count.try_into().unwrap()) as usize)
+            .ok_or_else(invalid_index)?;
+        let entries = &data[4..];
+        if entries.len() != count * 8 {
+            return Err(invalid_index());
+        }
+        let blocks = entries
+            .chunks_exact(8)
+            .map(|entry| Block {
+                compressed_len: u32::from_le_bytes(entry[..4].try_into().unwrap()),
+                uncompressed_len: u32::from_le_bytes(entry[4..].try_into().unwrap()),
+            })
+            .collect();
+        Ok(Self { blocks })
+    }
+}
+
+fn invalid_index() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "invalid gzip block index")
+}
+
+/// Compresses each block as its own gzip member, on up to `threads` threads, and writes them with
+/// a `BlockIndex` in the first member. Blocks must be non-empty and at most `u32::MAX` bytes, and
+/// there can be at most `BlockIndex::MAX_BLOCKS` of them.
+pub fn write_indexed<W: Write, B: AsRef<[u8]> + Sync>(
+    writer: &mut W,
+    blocks: &[B],
+    level: Compression,
+    threads: usize,
+) -> io::Result<BlockIndex> {
+    if blocks.is_empty() || blocks.len() > BlockIndex::MAX_BLOCKS {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "unsupported number of blocks",
+        ));
+    }
+    let (first, rest) = (blocks[0].as_ref(), &blocks[1..]);
+    let mut members = compress_members(rest, level, threads)?;
+    let mut index = BlockIndex {
+        blocks: std::iter::once(Block {
+            compressed_len: 0,
+            uncompressed_len: first.len() as u32,
+        })
+        .chain(rest.iter().zip(&members).map(|(block, member)| Block {
+            compressed_len: member.len() as u32,
+            uncompressed_len: block.as_ref().len() as u32,
+        }))
+        .collect(),
+    };
+
+    // The first member's size depends on the size of the index, but not on its contents, so it
+    // is compressed once to measure it and again with the final index.
+    let compress_first = |index: &BlockIndex| {
+        let mut encoder = deterministic_header()
+            .extra(index.to_extra())
+            .write(Vec::new(), level);
+        encoder.write_all(first)?;
+        encoder.finish()
+    };
+    index.blocks[0].compressed_len = compress_first(&index)?.len() as u32;
+    members.insert(0, compress_first(&index)?);
+
+    for member in members {
+        writer.write_all(&member)?;
+    }
+    Ok(index)
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(self.block_size - self.current.len());
+        self.current.extend_from_slice(&buf[..count]);
+        if self.current.len() == self.block_size {
+            let block = std::mem::replace(&mut self.current, Vec::with_capacity(self.block_size));
+            self.blocks.push(block);
+            if self.blocks.len() == self.threads {
+                self.compress_blocks()?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Compresses and writes all complete blocks. Partial blocks are kept until they fill up, so
+    /// flushing doesn't produce tiny members.
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress_blocks()?;
+        self.writer().flush()
+    }
+}
+
+impl<W: Write> Drop for ParallelGzEncoder<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, MultiGzDecoder};
+
+    #[test]
+    fn test_parallel_gzip() {
+        let data = (0..10_000u32)
+            .flat_map(|n| (n % 251).to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut encoder =
+            ParallelGzEncoder::with_block_size(Vec::new(), Compression::fast(), 3, 4096);
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        // A single member decoder stops after the first block.
+        let mut first = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut first)
+            .unwrap();
+        assert_eq!(first, data[..4096]);
+
+        let empty = ParallelGzEncoder::new(Vec::new(), Compression::best(), 2)
+            .finish()
+            .unwrap();
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(empty.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert!(decompressed.is_empty());
+
+        // Dropping the encoder finishes the stream.
+        let mut dropped = Vec::new();
+        {
+            let mut encoder =
+                ParallelGzEncoder::with_block_size(&mut dropped, Compression::fast(), 3, 4096);
+            encoder.write_all(&data).unwrap();
+        }
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(dropped.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn test_block_index() {
+        let blocks = [
+            b"header".to_vec(),
+            vec![1; 1000],
+            vec![2; 10],
+            vec![3; 5000],
+        ];
+        let mut compressed = Vec::new();
+        let index = write_indexed(&mut compressed, &blocks, Compression::default(), 2).unwrap();
+
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, blocks.concat());
+
+        assert_eq!(
+            BlockIndex::read_from(&mut compressed.as_slice()).unwrap(),
+            Some(index.clone())
+        );
+        assert_eq!(index.uncompressed_len(), decompressed.len() as u64);
+        let total = index
+            .blocks
+            .iter()
+            .map(|b| b.compressed_len as usize)
+            .sum::<usize>();
+        assert_eq!(total, compressed.len());
+        for ((compressed_offset, _), (block, expected)) in
+            index.offsets().zip(index.blocks.iter().zip(&blocks))
+        {
+            let start = compressed_offset as usize;
+            let member = &compressed[start..start + block.compressed_len as usize];
+            let mut data = Vec::new();
+            GzDecoder::new(member).read_to_end(&mut data).unwrap();
+            assert_eq!(&data, expected);
+        }
+
+        let plain = ParallelGzEncoder::new(Vec::new(), Compression::fast(), 1)
+            .finish()
+            .unwrap();
+        assert_eq!(BlockIndex::read_from(&mut plain.as_slice()).unwrap(), None);
+    }
+}
@@ -111,7 +111,9 @@ fn hilbert_index_3d(mut x: u32, mut y: u32, mut z: u32, bits: u32) -> u64 {
     index
 }
 
-pub(crate) fn hilbert_sort<T, F>(points: &Vec<T>, position: F) -> Vec<T>
+/// Sorts `points` along a 3D Hilbert curve through their bounding box, so nearby points end up
+/// close together. Points with the same curve index keep their order.
+pub fn hilbert_sort<T, F>(points: &Vec<T>, position: F) -> Vec<T>
 where
     T: Clone,
     F: Fn(&T) -> Vec3<f32>,
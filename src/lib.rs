@@ -1,4 +1,11 @@
+pub mod codec;
+pub mod error;
 pub mod fixedpoint24;
+pub mod gaussian_cloud;
+pub mod gzip;
+pub mod hilbert_curve;
+mod nearest_neighbours;
+pub mod packed_gaussians;
 pub mod ply_format;
 pub mod spherical_harmonics;
 pub mod spz_format;
@@ -6,3 +13,8 @@ pub mod spz_reader;
 pub mod spz_writer;
 mod support;
 pub mod unpacked_gaussian;
+
+pub use codec::{decode, encode, DecodeOptions, EncodeOptions};
+pub use error::{Column, SpzError};
+pub use gaussian_cloud::{ExtraAttribute, GaussianCloud, ShDegreePolicy};
+pub use packed_gaussians::PackedGaussians;
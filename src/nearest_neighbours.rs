@@ -0,0 +1,122 @@
+use vek::Vec3;
+
+/// A static k-d tree over a set of points, for nearest neighbour queries.
+///
+/// The tree is implicit: each range of `indices` is split at its middle element, which holds the
+/// median along the node's axis.
+struct KdTree<'a> {
+    points: &'a [Vec3<f32>],
+    indices: Vec<usize>,
+}
+
+impl<'a> KdTree<'a> {
+    fn new(points: &'a [Vec3<f32>]) -> Self {
+        let mut indices = (0..points.len()).collect::<Vec<_>>();
+        Self::build(points, &mut indices, 0);
+        Self { points, indices }
+    }
+
+    fn build(points: &[Vec3<f32>], indices: &mut [usize], depth: usize) {
+        if indices.len() <= 1 {
+            return;
+        }
+        let axis = depth % 3;
+        let middle = indices.len() / 2;
+        indices
+            .select_nth_unstable_by(middle, |&a, &b| points[a][axis].total_cmp(&points[b][axis]));
+        let (left, right) = indices.split_at_mut(middle);
+        Self::build(points, left, depth + 1);
+        Self::build(points, &mut right[1..], depth + 1);
+    }
+
+    /// The squared distances from the point at `index` to its `k` nearest other points, closest
+    /// first. Fewer if there aren't `k` other points.
+    fn nearest(&self, index: usize, k: usize) -> Vec<f32> {
+        let mut nearest = Vec::with_capacity(k + 1);
+        self.search(index, k, 0..self.indices.len(), 0, &mut nearest);
+        nearest
+    }
+
+    fn search(
+        &self,
+        index: usize,
+        k: usize,
+        range: std::ops::Range<usize>,
+        depth: usize,
+        nearest: &mut Vec<f32>,
+    ) {
+        if range.is_empty() {
+            return;
+        }
+        let query = self.points[index];
+        let middle = range.start + range.len() / 2;
+        let candidate = self.indices[middle];
+        if candidate != index {
+            let distance = query.distance_squared(self.points[candidate]);
+            if nearest.len() < k || distance < nearest[k - 1] {
+                let position = nearest.partition_point(|&d| d <= distance);
+                nearest.insert(position, distance);
+                nearest.truncate(k);
+            }
+        }
+
+        let axis = depth % 3;
+        let offset = query[axis] - self.points[candidate][axis];
+        let (near, far) = if offset < 0.0 {
+            (range.start..middle, middle + 1..range.end)
+        } else {
+            (middle + 1..range.end, range.start..middle)
+        };
+        self.search(index, k, near, depth + 1, nearest);
+        if nearest.len() < k || offset * offset < nearest[k - 1] {
+            self.search(index, k, far, depth + 1, nearest);
+        }
+    }
+}
+
+/// The mean squared distance from each point to its `k` nearest neighbours, or `None` for a point
+/// without neighbours.
+pub(crate) fn mean_squared_distances(points: &[Vec3<f32>], k: usize) -> Vec<Option<f32>> {
+    let tree = KdTree::new(points);
+    (0..points.len())
+        .map(|index| {
+            let nearest = tree.nearest(index, k);
+            (!nearest.is_empty()).then(|| nearest.iter().sum::<f32>() / nearest.len() as f32)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mean_squared_distances() {
+        let points = (0..1000)
+            .map(|i| {
+                Vec3::new(
+                    (i % 10) as f32,
+                    (i / 10 % 10) as f32,
+                    (i / 100) as f32 * 2.0,
+                )
+            })
+            .collect::<Vec<_>>();
+        let tree = KdTree::new(&points);
+        for index in [0, 1, 555, 999] {
+            let mut expected = points
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != index)
+                .map(|(_, p)| p.distance_squared(points[index]))
+                .collect::<Vec<_>>();
+            expected.sort_by(f32::total_cmp);
+            assert_eq!(tree.nearest(index, 4), expected[..4]);
+        }
+
+        assert_eq!(
+            mean_squared_distances(&points[..3], 3),
+            [Some(2.5), Some(1.0), Some(2.5)]
+        );
+        assert_eq!(mean_squared_distances(&points[..1], 3), [None]);
+    }
+}
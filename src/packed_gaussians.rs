@@ -0,0 +1,509 @@
+use vek::{Quaternion, Vec3};
+
+use crate::error::{Column, Result, SpzError};
+use crate::fixedpoint24::{compute_fixed_point_fractional_bits, FixedPoint24};
+use crate::gaussian_cloud::GaussianCloud;
+use crate::spz_format::*;
+use crate::spz_writer::SPZWriterOptions;
+use crate::support::{half_to_float, inv_sigmoid, sigmoid, to_u8};
+
+/// The quantized columns of an SPZ file, byte for byte as stored.
+///
+/// Reordering, subsetting or concatenating packed gaussians never touches the quantized values,
+/// so no precision is lost no matter how often a file is rewritten.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PackedGaussians {
+    pub num_points: usize,
+    pub version: u32,
+    pub sh_degree: u8,
+    pub fractional_bits: u8,
+    pub flags: SPZFlags,
+    pub positions: Vec<u8>,
+    pub alphas: Vec<u8>,
+    pub colors: Vec<u8>,
+    pub scales: Vec<u8>,
+    pub rotations: Vec<u8>,
+    pub spherical_harmonics: Vec<u8>,
+}
+
+impl PackedGaussians {
+    /// Creates an empty set of columns described by `header`. `num_points` is left at zero.
+    pub fn from_header(header: &SPZHeader) -> Self {
+        PackedGaussians {
+            version: header.version,
+            sh_degree: header.sh_degree,
+            fractional_bits: header.fractional_bits,
+            flags: header.flags(),
+            ..Default::default()
+        }
+    }
+
+    pub fn header(&self) -> SPZHeader {
+        SPZHeader::new(
+            self.version,
+            self.num_points as u32,
+            self.sh_degree,
+            self.fractional_bits,
+            self.flags,
+        )
+    }
+
+    /// Quantizes a cloud the way `SPZWriter` does.
+    pub fn pack(cloud: &GaussianCloud, options: &SPZWriterOptions) -> Result<Self> {
+        let mut packed = Self::pack_without_positions(cloud, options)?;
+        let positions: Vec<f32> = cloud
+            .positions
+            .iter()
+            .flat_map(|p| p.iter())
+            .cloned()
+            .collect();
+        let fractional_bits = options.fractional_bits.map_or_else(
+            || compute_fixed_point_fractional_bits(&positions, 24),
+            usize::from,
+        );
+        packed.fractional_bits = fractional_bits as u8;
+        for &v in &positions {
+            let f = FixedPoint24::new(v);
+            packed.positions.extend_from_slice(&f.into(fractional_bits));
+        }
+        Ok(packed)
+    }
+
+    /// Quantizes every column but the positions, which are left empty along with
+    /// `fractional_bits`, since they depend on the extent of the whole cloud.
+    pub(crate) fn pack_without_positions(
+        cloud: &GaussianCloud,
+        options: &SPZWriterOptions,
+    ) -> Result<Self> {
+        let version = options.version;
+        if !(2..=3).contains(&version) {
+            return Err(SpzError::UnsupportedVersion(version));
+        }
+        check_sh_bits(options.sh1_bits)?;
+        check_sh_bits(options.sh_rest_bits)?;
+        check_fractional_bits(options.fractional_bits)?;
+        cloud.validate()?;
+        let cloud = cloud.with_max_sh_degree(options.max_sh_degree)?;
+
+        let mut flags = SPZFlags::empty();
+        flags.set(SPZFlags::ANTIALIASED, cloud.antialiased);
+        let mut packed = PackedGaussians {
+            num_points: cloud.num_points,
+            version,
+            sh_degree: cloud.sh_degree,
+            flags,
+            ..Default::default()
+        };
+
+        for &alpha in &cloud.alphas {
+            packed.alphas.push(to_u8(sigmoid(alpha) * 255.0));
+        }
+
+        for color in &cloud.colors {
+            for &v in color {
+                packed
+                    .colors
+                    .push(to_u8(v * (COLOR_SCALE * 255.0) + 0.5 * 255.0));
+            }
+        }
+
+        for scales in &cloud.scales {
+            for &v in scales {
+                packed.scales.push(to_u8((v + 10.0) * 16.0));
+            }
+        }
+
+        for &rotation in &cloud.rotations {
+            if version >= 3 {
+                let q: SPZSmallestThreeQuaternion = rotation.into();
+                packed.rotations.extend_from_slice(&q.0);
+            } else {
+                let q = rotation.normalized();
+                let q: SPZQuaternion = if q.w < 0.0 { -q } else { q }.into();
+                packed.rotations.extend_from_slice(&q.0);
+            }
+        }
+
+        packed.spherical_harmonics = sh_scalars_to_spz_bytes(
+            &cloud.spherical_harmonics,
+            cloud.sh_scalar_count(),
+            options.sh1_bits,
+            options.sh_rest_bits,
+        );
+
+        Ok(packed)
+    }
+
+    /// Decodes all columns into a cloud, exactly as `SPZReader::read` would.
+    pub fn unpack(&self) -> Result<GaussianCloud> {
+        self.validate()?;
+        let header = self.header();
+        Ok(GaussianCloud {
+            num_points: self.num_points,
+            sh_degree: self.sh_degree,
+            antialiased: header.is_antialiased(),
+            positions: decode_positions(&self.positions, &header),
+            rotations: decode_rotations(&self.rotations, &header),
+            scales: decode_scales(&self.scales),
+            colors: decode_colors(&self.colors),
+            alphas: decode_alphas(&self.alphas),
+            spherical_harmonics: sh_scalars_from_spz_bytes(&self.spherical_harmonics),
+            extra_attributes: Vec::new(),
+        })
+    }
+
+    /// The columns in the order they are stored.
+    pub(crate) fn columns(&self) -> [(Column, &Vec<u8>); 6] {
+        [
+            (Column::Positions, &self.positions),
+            (Column::Alphas, &self.alphas),
+            (Column::Colors, &self.colors),
+            (Column::Scales, &self.scales),
+            (Column::Rotations, &self.rotations),
+            (Column::SphericalHarmonics, &self.spherical_harmonics),
+        ]
+    }
+
+    fn columns_mut(&mut self) -> [(Column, &mut Vec<u8>); 6] {
+        [
+            (Column::Positions, &mut self.positions),
+            (Column::Alphas, &mut self.alphas),
+            (Column::Colors, &mut self.colors),
+            (Column::Scales, &mut self.scales),
+            (Column::Rotations, &mut self.rotations),
+            (Column::SphericalHarmonics, &mut self.spherical_harmonics),
+        ]
+    }
+
+    /// Checks the header fields and that every column holds `num_points` entries.
+    pub fn validate(&self) -> Result<()> {
+        let header = self.header();
+        if !(1..=3).contains(&self.version) {
+            return Err(SpzError::UnsupportedVersion(self.version));
+        }
+        if self.sh_degree > 3 {
+            return Err(SpzError::InvalidShDegree(self.sh_degree));
+        }
+        for (column, data) in self.columns() {
+            let expected = self.num_points * header.bytes_per_point(column);
+            if data.len() != expected {
+                return Err(SpzError::ColumnLength {
+                    column,
+                    expected,
+                    actual: data.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reorders the gaussians so that gaussian `i` of the result is gaussian `order[i]` of `self`.
+    /// `order` must contain every index exactly once.
+    pub fn permute(&self, order: &[usize]) -> Result<Self> {
+        let mut seen = vec![false; self.num_points];
+        for &index in order {
+            match seen.get_mut(index) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(SpzError::InvalidPermutation),
+            }
+        }
+        if order.len() != self.num_points {
+            return Err(SpzError::InvalidPermutation);
+        }
+        self.select(order)
+    }
+
+    /// Returns the gaussians at `indices`, in that order.
+    pub fn subset(&self, indices: &[usize]) -> Result<Self> {
+        if let Some(&index) = indices.iter().find(|&&index| index >= self.num_points) {
+            return Err(SpzError::IndexOutOfRange {
+                index,
+                len: self.num_points,
+            });
+        }
+        self.select(indices)
+    }
+
+    fn select(&self, indices: &[usize]) -> Result<Self> {
+        self.validate()?;
+        let header = self.header();
+        let mut result = PackedGaussians {
+            num_points: indices.len(),
+            ..PackedGaussians::from_header(&header)
+        };
+        for ((column, source), (_, destination)) in
+            self.columns().into_iter().zip(result.columns_mut())
+        {
+            let stride = header.bytes_per_point(column);
+            destination.reserve(indices.len() * stride);
+            for &index in indices {
+                destination.extend_from_slice(&source[index * stride..(index + 1) * stride]);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Appends the gaussians of all `parts`. The parts must share the same version, spherical
+    /// harmonics degree, fractional bits and flags, since those apply to the whole file.
+    pub fn concatenate(parts: &[PackedGaussians]) -> Result<Self> {
+        let first = parts.first().ok_or(SpzError::NoGaussians)?;
+        let mut result = PackedGaussians::from_header(&first.header());
+        for part in parts {
+            part.validate()?;
+            if part.version != first.version {
+                return Err(SpzError::IncompatiblePacked("version"));
+            }
+            if part.sh_degree != first.sh_degree {
+                return Err(SpzError::IncompatiblePacked("spherical harmonics degree"));
+            }
+            if part.fractional_bits != first.fractional_bits {
+                return Err(SpzError::IncompatiblePacked("fractional bits"));
+            }
+            if part.flags != first.flags {
+                return Err(SpzError::IncompatiblePacked("flags"));
+            }
+            result.num_points += part.num_points;
+            for ((_, source), (_, destination)) in
+                part.columns().into_iter().zip(result.columns_mut())
+            {
+                destination.extend_from_slice(source);
+            }
+        }
+        Ok(result)
+    }
+}
+
+pub(crate) fn decode_positions(data: &[u8], header: &SPZHeader) -> Vec<Vec3<f32>> {
+    let positions: Vec<f32> = if header.version == 1 {
+        data.chunks_exact(2)
+            .map(|chunk| half_to_float(u16::from_le_bytes([chunk[0], chunk[1]])))
+            .collect()
+    } else {
+        data.chunks_exact(3)
+            .map(|chunk| {
+                FixedPoint24::from(
+                    [chunk[0], chunk[1], chunk[2]],
+                    header.fractional_bits as usize,
+                )
+                .0
+            })
+            .collect()
+    };
+    positions
+        .chunks(3)
+        .map(|chunk| Vec3::new(chunk[0], chunk[1], chunk[2]))
+        .collect()
+}
+
+/// Bytes 0 and 255 would decode to infinite alphas, so decoding stays a quarter step inside the
+/// range. These still quantize back to the same bytes.
+const MIN_ALPHA: f32 = 0.25 / 255.0;
+const MAX_ALPHA: f32 = 254.75 / 255.0;
+
+pub(crate) fn decode_alphas(data: &[u8]) -> Vec<f32> {
+    data.iter()
+        .map(|&v| inv_sigmoid((v as f32 / 255.0).clamp(MIN_ALPHA, MAX_ALPHA)))
+        .collect()
+}
+
+pub(crate) fn decode_colors(data: &[u8]) -> Vec<Vec3<f32>> {
+    data.iter()
+        .map(|&v| (v as f32 / 255.0 - 0.5) / COLOR_SCALE)
+        .collect::<Vec<_>>()
+        .chunks(3)
+        .map(|chunk| Vec3::new(chunk[0], chunk[1], chunk[2]))
+        .collect()
+}
+
+pub(crate) fn decode_scales(data: &[u8]) -> Vec<Vec3<f32>> {
+    data.iter()
+        .map(|&v| v as f32 / 16.0 - 10.0)
+        .collect::<Vec<_>>()
+        .chunks(3)
+        .map(|chunk| Vec3::new(chunk[0], chunk[1], chunk[2]))
+        .collect()
+}
+
+pub(crate) fn decode_rotations(data: &[u8], header: &SPZHeader) -> Vec<Quaternion<f32>> {
+    data.chunks_exact(header.rotation_size())
+        .map(|chunk| {
+            if header.version >= 3 {
+                SPZSmallestThreeQuaternion([chunk[0], chunk[1], chunk[2], chunk[3]]).into()
+            } else {
+                SPZQuaternion([chunk[0], chunk[1], chunk[2]]).into()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spz_reader::{SPZReader, SPZReaderOptions};
+    use crate::spz_writer::SPZWriter;
+    use crate::unpacked_gaussian::UnpackedGaussian;
+
+    fn packed(count: usize) -> PackedGaussians {
+        let gaussians = (0..count)
+            .map(|n| UnpackedGaussian {
+                position: Vec3::new(n as f32, -(n as f32), 0.5),
+                alpha: n as f32 / 10.0,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+        PackedGaussians::pack(&cloud, &SPZWriterOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_packed_round_trip() {
+        let packed = packed(4);
+        assert!(packed.validate().is_ok());
+
+        let mut buffer = Vec::new();
+        SPZWriter::new(&mut buffer, SPZWriterOptions::default())
+            .write_packed(&packed)
+            .unwrap();
+        let read =
+            SPZReader::new_from_slice(&buffer, SPZReaderOptions::default().skip_compression(true))
+                .read_packed()
+                .unwrap();
+        assert_eq!(read, packed);
+
+        let cloud =
+            SPZReader::new_from_slice(&buffer, SPZReaderOptions::default().skip_compression(true))
+                .read()
+                .unwrap();
+        assert_eq!(packed.unpack().unwrap(), cloud);
+    }
+
+    #[test]
+    fn test_permute_subset_concatenate() {
+        let packed = packed(4);
+
+        let permuted = packed.permute(&[3, 2, 1, 0]).unwrap();
+        assert_eq!(
+            permuted.alphas,
+            packed.alphas.iter().rev().cloned().collect::<Vec<_>>()
+        );
+        assert_eq!(permuted.permute(&[3, 2, 1, 0]).unwrap(), packed);
+        assert!(matches!(
+            packed.permute(&[0, 0, 1, 2]),
+            Err(SpzError::InvalidPermutation)
+        ));
+        assert!(matches!(
+            packed.permute(&[0, 1]),
+            Err(SpzError::InvalidPermutation)
+        ));
+
+        let head = packed.subset(&[0, 1]).unwrap();
+        let tail = packed.subset(&[2, 3]).unwrap();
+        assert_eq!(head.num_points, 2);
+        assert_eq!(head.positions, packed.positions[..18]);
+        assert!(matches!(
+            packed.subset(&[4]),
+            Err(SpzError::IndexOutOfRange { index: 4, len: 4 })
+        ));
+
+        let concatenated = PackedGaussians::concatenate(&[head, tail]).unwrap();
+        assert_eq!(concatenated, packed);
+
+        let mut other = packed.clone();
+        other.fractional_bits += 1;
+        assert!(matches!(
+            PackedGaussians::concatenate(&[packed, other]),
+            Err(SpzError::IncompatiblePacked("fractional bits"))
+        ));
+    }
+
+    #[test]
+    fn test_reference_quantization() {
+        // The first gaussian is the one from `test_single`, as encoded by the reference
+        // implementation. The second exercises clamping; its bytes, and the version 3 rotations
+        // below, are worked out by hand from the reference `packGaussians` and
+        // `packQuaternionSmallestThree`, not taken from reference output.
+        let gaussians = [
+            UnpackedGaussian {
+                position: Vec3::new(100.0, 200.0, -100.0),
+                rotation: Quaternion::from_xyzw(0.0, 0.0, 1.0, 0.0),
+                scales: Vec3::new(1.0, -1.0, 1.0),
+                color: Vec3::new(1.0, 0.5, 0.25),
+                alpha: 0.95,
+                ..Default::default()
+            },
+            UnpackedGaussian {
+                position: Vec3::new(-1.5, 0.25, 3.0),
+                rotation: Quaternion::from_xyzw(0.5, -0.5, 0.5, -0.5),
+                scales: Vec3::new(-12.0, 5.4, -3.3),
+                color: Vec3::new(-5.0, 0.0, 2.0),
+                alpha: 10.0,
+                ..Default::default()
+            },
+        ];
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+        let options = SPZWriterOptions {
+            version: 2,
+            ..Default::default()
+        };
+        let packed = PackedGaussians::pack(&cloud, &options).unwrap();
+        assert_eq!(packed.alphas, [0xB8, 0xFF]);
+        assert_eq!(packed.colors, [0xA6, 0x93, 0x89, 0, 128, 204]);
+        assert_eq!(packed.scales, [0xB0, 0x90, 0xB0, 0, 246, 107]);
+        assert_eq!(packed.rotations, [0x80, 0x80, 0xFF, 64, 191, 64]);
+        let v3 = SPZWriterOptions {
+            version: 3,
+            ..Default::default()
+        };
+        // Largest component index 2, the rest zero; then index 0 with magnitudes of
+        // round(511 * 0.5 / sqrt(0.5)) = 361 and sign bits on y and w.
+        assert_eq!(
+            PackedGaussians::pack(&cloud, &v3).unwrap().rotations,
+            [0x00, 0x00, 0x00, 0x80, 0x69, 0xA7, 0x95, 0x36]
+        );
+
+        // With the reference's fixed 12 fractional bits, the first gaussian is written byte for
+        // byte as the reference file in `test_single`.
+        let reference_options = SPZWriterOptions {
+            version: 2,
+            fractional_bits: Some(12),
+            ..Default::default()
+        };
+        let reference = PackedGaussians::pack(&cloud, &reference_options).unwrap();
+        let mut bytes = Vec::new();
+        SPZWriter::new(&mut bytes, reference_options)
+            .write_packed(&reference.subset(&[0]).unwrap())
+            .unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x4E, 0x47, 0x53, 0x50, 0x02, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0C,
+                0x00, 0x00, 0x00, 0x40, 0x06, 0x00, 0x80, 0x0C, 0x00, 0xC0, 0xF9, 0xB8, 0xA6, 0x93,
+                0x89, 0xB0, 0x90, 0xB0, 0x80, 0x80, 0xFF
+            ]
+        );
+        // The default picks the fractional bits from the data instead.
+        assert_eq!(packed.fractional_bits, 15);
+
+        // Decoding is always finite, and quantizes back to the same bytes.
+        let unpacked = reference.unpack().unwrap();
+        assert!(unpacked.to_gaussians().iter().all(|g| g.is_valid()));
+        assert_eq!(
+            PackedGaussians::pack(&unpacked, &options).unwrap().alphas,
+            packed.alphas
+        );
+        let extremes = PackedGaussians {
+            num_points: 2,
+            alphas: vec![0, 255],
+            ..reference.subset(&[0, 1]).unwrap()
+        };
+        let alphas = extremes.unpack().unwrap().alphas;
+        assert!(alphas.iter().all(|alpha| alpha.is_finite()));
+        assert_eq!(
+            alphas
+                .iter()
+                .map(|&a| to_u8(sigmoid(a) * 255.0))
+                .collect::<Vec<_>>(),
+            [0, 255]
+        );
+    }
+}
@@ -1,5 +1,8 @@
+use crate::error::{Result, SpzError};
+use crate::gaussian_cloud::{ExtraAttribute, GaussianCloud};
+use crate::spherical_harmonics::SphericalHarmonicsOrder;
+use crate::support::{half_to_float, inv_sigmoid, linear_to_sph0, sigmoid, sph0_to_linear};
 use crate::unpacked_gaussian::UnpackedGaussian;
-use anyhow::Result;
 use clap::ValueEnum;
 use ply_rs::parser;
 use ply_rs::ply;
@@ -8,85 +11,601 @@ use ply_rs::ply::{
     ScalarType,
 };
 use ply_rs::writer::Writer;
-use std::io::{BufRead, Write};
+use std::collections::HashSet;
+use std::f32::consts::{FRAC_1_SQRT_2, SQRT_2};
+use std::io::{BufRead, Read, Write};
 use std::path::Path;
+use vek::{Quaternion, Vec3};
 
-impl ply::PropertyAccess for UnpackedGaussian {
-    fn new() -> Self {
-        UnpackedGaussian::default()
-    }
-
-    fn set_property(&mut self, property_name: String, property: ply::Property) {
-        match (property_name.as_ref(), property) {
-            ("x", ply::Property::Float(v)) => self.position[0] = v,
-            ("y", ply::Property::Float(v)) => self.position[1] = v,
-            ("z", ply::Property::Float(v)) => self.position[2] = v,
-            ("rot_0", ply::Property::Float(v)) => self.rotation.x = v,
-            ("rot_1", ply::Property::Float(v)) => self.rotation.y = v,
-            ("rot_2", ply::Property::Float(v)) => self.rotation.z = v,
-            ("rot_3", ply::Property::Float(v)) => self.rotation.w = v,
-            ("scale_0", ply::Property::Float(v)) => self.scales[0] = v,
-            ("scale_1", ply::Property::Float(v)) => self.scales[1] = v,
-            ("scale_2", ply::Property::Float(v)) => self.scales[2] = v,
-            ("opacity", ply::Property::Float(v)) => self.alpha = v,
-            ("nx", _) => (),
-            ("ny", _) => (),
-            ("nz", _) => (),
-            ("f_dc_0", ply::Property::Float(v)) => self.color[0] = v,
-            ("f_dc_1", ply::Property::Float(v)) => self.color[1] = v,
-            ("f_dc_2", ply::Property::Float(v)) => self.color[2] = v,
-            (name, ply::Property::Float(v)) if name.starts_with("f_rest_") => {
-                let index: usize = name["f_rest_".len()..].parse().unwrap();
-                self.spherical_harmonics.extend_scalar(index, v);
+/// The column a gaussian vertex property is read into. Unknown properties and the normals have
+/// none, and `f_rest` coefficients are mapped by `load_ply_stream`, since their order depends on
+/// how many there are.
+fn gaussian_target(name: &str) -> Option<Target> {
+    Some(match name {
+        "x" => Target::Position(0),
+        "y" => Target::Position(1),
+        "z" => Target::Position(2),
+        "rot_0" => Target::Rotation(0),
+        "rot_1" => Target::Rotation(1),
+        "rot_2" => Target::Rotation(2),
+        "rot_3" => Target::Rotation(3),
+        "scale_0" => Target::Scale(0),
+        "scale_1" => Target::Scale(1),
+        "scale_2" => Target::Scale(2),
+        "opacity" => Target::Alpha,
+        "f_dc_0" => Target::Color(0),
+        "f_dc_1" => Target::Color(1),
+        "f_dc_2" => Target::Color(2),
+        _ => return None,
+    })
+}
+
+/// 3DGS PLY files store the `f_rest` coefficients channel-major: all red coefficients, then all
+/// green, then all blue. Returns the RGB-interleaved index, as in `SphericalHarmonics::scalars`,
+/// of `f_rest_<index>` out of `count`.
+fn f_rest_to_scalar_index(index: usize, count: usize) -> usize {
+    let coefficients = count / 3;
+    (index % coefficients) * 3 + index / coefficients
+}
+
+/// Whether a vertex property is read into a gaussian column, or ignored like the normals.
+fn is_gaussian_property(name: &str) -> bool {
+    matches!(name, "nx" | "ny" | "nz")
+        || GAUSSIAN_PROPERTIES.contains(&name)
+        || f_rest_index(name).is_some()
+}
+
+fn scalar_size(data_type: &ScalarType) -> usize {
+    match data_type {
+        ScalarType::Char | ScalarType::UChar => 1,
+        ScalarType::Short | ScalarType::UShort => 2,
+        ScalarType::Int | ScalarType::UInt | ScalarType::Float => 4,
+        ScalarType::Double => 8,
+    }
+}
+
+/// Decodes a binary scalar of `scalar_size(data_type)` bytes.
+fn binary_scalar(bytes: &[u8], data_type: &ScalarType, big_endian: bool) -> f64 {
+    let mut le = [0; 8];
+    le[..bytes.len()].copy_from_slice(bytes);
+    if big_endian {
+        le[..bytes.len()].reverse();
+    }
+    let [b0, b1, b2, b3, ..] = le;
+    match data_type {
+        ScalarType::Char => b0 as i8 as f64,
+        ScalarType::UChar => b0 as f64,
+        ScalarType::Short => i16::from_le_bytes([b0, b1]) as f64,
+        ScalarType::UShort => u16::from_le_bytes([b0, b1]) as f64,
+        ScalarType::Int => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+        ScalarType::UInt => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+        ScalarType::Float => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+        ScalarType::Double => f64::from_le_bytes(le),
+    }
+}
+
+/// Parses an ASCII scalar, which must be in the range of its type.
+fn ascii_scalar(token: &str, data_type: &ScalarType) -> Option<f64> {
+    match data_type {
+        ScalarType::Char => token.parse::<i8>().ok().map(f64::from),
+        ScalarType::UChar => token.parse::<u8>().ok().map(f64::from),
+        ScalarType::Short => token.parse::<i16>().ok().map(f64::from),
+        ScalarType::UShort => token.parse::<u16>().ok().map(f64::from),
+        ScalarType::Int => token.parse::<i32>().ok().map(f64::from),
+        ScalarType::UInt => token.parse::<u32>().ok().map(f64::from),
+        ScalarType::Float => token.parse::<f32>().ok().map(f64::from),
+        ScalarType::Double => token.parse().ok(),
+    }
+}
+
+/// Reads the rows of an element with only scalar properties, passing each to `row` as the values
+/// in header order. The values are parsed into one buffer, reused for every row.
+fn read_element<T: BufRead>(
+    stream: &mut T,
+    element: &ElementDef,
+    encoding: Encoding,
+    mut row: impl FnMut(&[f64]),
+) -> Result<()> {
+    let data_types = element
+        .properties
+        .iter()
+        .map(|(name, property)| match &property.data_type {
+            PropertyType::Scalar(data_type) => Ok(data_type),
+            PropertyType::List(..) => Err(SpzError::UnsupportedPlyProperty {
+                element: element.name.clone(),
+                name: name.clone(),
+            }),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut values = vec![0.0; data_types.len()];
+    let truncated = || {
+        SpzError::PlyParse(format!(
+            "{} has fewer than {} rows",
+            element.name, element.count
+        ))
+    };
+    if encoding == Encoding::Ascii {
+        let mut line = String::new();
+        for _ in 0..element.count {
+            line.clear();
+            if stream.read_line(&mut line)? == 0 {
+                return Err(truncated());
+            }
+            let mut tokens = line.split_ascii_whitespace();
+            for (value, data_type) in values.iter_mut().zip(&data_types) {
+                *value = tokens
+                    .next()
+                    .and_then(|token| ascii_scalar(token, data_type))
+                    .ok_or_else(|| {
+                        SpzError::PlyParse(format!(
+                            "invalid {} row {:?}",
+                            element.name,
+                            line.trim_end()
+                        ))
+                    })?;
             }
-            (k, _) => panic!("Vertex: Unexpected key/value combination: key: {}", k),
+            row(&values);
         }
+    } else {
+        let big_endian = encoding == Encoding::BinaryBigEndian;
+        let mut bytes = vec![0; data_types.iter().map(|t| scalar_size(t)).sum()];
+        for _ in 0..element.count {
+            stream.read_exact(&mut bytes).map_err(|e| match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => truncated(),
+                _ => e.into(),
+            })?;
+            let mut offset = 0;
+            for (value, data_type) in values.iter_mut().zip(&data_types) {
+                let size = scalar_size(data_type);
+                *value = binary_scalar(&bytes[offset..offset + size], data_type, big_endian);
+                offset += size;
+            }
+            row(&values);
+        }
+    }
+    Ok(())
+}
+
+/// The rows of an element read whole, for elements that refer to each other.
+#[derive(Default)]
+struct PlyRows {
+    values: Vec<f64>,
+    width: usize,
+    len: usize,
+}
+
+impl PlyRows {
+    fn read<T: BufRead>(stream: &mut T, element: &ElementDef, encoding: Encoding) -> Result<Self> {
+        let mut rows = PlyRows {
+            values: Vec::new(),
+            width: element.properties.len(),
+            len: 0,
+        };
+        read_element(stream, element, encoding, |row| {
+            rows.values.extend_from_slice(row);
+            rows.len += 1;
+        })?;
+        Ok(rows)
     }
+
+    fn row(&self, index: usize) -> &[f64] {
+        &self.values[index * self.width..][..self.width]
+    }
+}
+
+/// Converts a value to a property of `data_type`. Values are saturated to the range of integer
+/// types.
+fn scalar_property(value: f64, data_type: &ScalarType) -> Property {
+    match data_type {
+        ScalarType::Char => Property::Char(value as i8),
+        ScalarType::UChar => Property::UChar(value as u8),
+        ScalarType::Short => Property::Short(value as i16),
+        ScalarType::UShort => Property::UShort(value as u16),
+        ScalarType::Int => Property::Int(value as i32),
+        ScalarType::UInt => Property::UInt(value as u32),
+        ScalarType::Float => Property::Float(value as f32),
+        ScalarType::Double => Property::Double(value),
+    }
+}
+
+const GAUSSIAN_PROPERTIES: [&str; 14] = [
+    "x", "y", "z", "rot_0", "rot_1", "rot_2", "rot_3", "scale_0", "scale_1", "scale_2", "opacity",
+    "f_dc_0", "f_dc_1", "f_dc_2",
+];
+
+/// The index of an `f_rest_<n>` property, if `name` is one.
+fn f_rest_index(name: &str) -> Option<usize> {
+    name.strip_prefix("f_rest_")?.parse().ok()
+}
+
+/// Whether the vertices are a plain colored point cloud, `x y z red green blue` without any
+/// gaussian splat properties, as written by photogrammetry and LiDAR tools.
+fn is_point_cloud(element: &ElementDef) -> bool {
+    let has = |name: &str| element.properties.contains_key(name);
+    ["x", "y", "z", "red", "green", "blue"].into_iter().all(has)
+        && !element.properties.keys().any(|name| {
+            is_gaussian_property(name)
+                && !matches!(name.as_str(), "x" | "y" | "z" | "nx" | "ny" | "nz")
+        })
 }
 
-pub fn load_ply_stream<T: BufRead>(stream: &mut T) -> Result<Vec<UnpackedGaussian>> {
-    let gaussian_parser = parser::Parser::<UnpackedGaussian>::new();
-    let header = gaussian_parser.read_header(stream)?;
-    let mut gaussian_list = Vec::new();
+fn color_channel(name: &str) -> Option<usize> {
+    ["red", "green", "blue"].iter().position(|&c| c == name)
+}
+
+/// The value that stands for 1.0 in an integer property holding a fraction, like a color channel
+/// or an opacity. `None` for floating point types, which hold the value itself.
+fn full_scale(data_type: &ScalarType) -> Option<f32> {
+    match data_type {
+        ScalarType::Char => Some(i8::MAX as f32),
+        ScalarType::UChar => Some(u8::MAX as f32),
+        ScalarType::Short => Some(i16::MAX as f32),
+        ScalarType::UShort => Some(u16::MAX as f32),
+        ScalarType::Int => Some(i32::MAX as f32),
+        ScalarType::UInt => Some(u32::MAX as f32),
+        ScalarType::Float | ScalarType::Double => None,
+    }
+}
+
+/// Converts an opacity stored as a fraction of `full_scale` to a logit. Opacities of 0 and 1 are
+/// pulled inside the range, so the logit is finite.
+fn opacity_logit(value: f32, full_scale: f32) -> f32 {
+    inv_sigmoid((value / full_scale).clamp(1e-6, 1.0 - 1e-6))
+}
+
+/// `PropertyAccess::set_property` can't fail, so properties are checked against the header before
+/// any vertex is parsed. Returns the extra attributes, without values, in header order, and the
+/// number of `f_rest` properties.
+fn check_vertex_properties(element: &ElementDef) -> Result<(Vec<ExtraAttribute>, usize)> {
+    let point_cloud = is_point_cloud(element);
+    let mut extra_attributes = Vec::new();
+    let mut f_rest_indices = Vec::new();
+    for (name, property) in &element.properties {
+        let PropertyType::Scalar(data_type) = &property.data_type else {
+            return Err(SpzError::UnsupportedPlyProperty {
+                element: element.name.clone(),
+                name: name.clone(),
+            });
+        };
+        if let Some(index) = f_rest_index(name) {
+            f_rest_indices.push(index);
+        } else if !(is_gaussian_property(name) || point_cloud && color_channel(name).is_some()) {
+            extra_attributes.push(ExtraAttribute {
+                name: name.to_string(),
+                data_type: data_type.clone(),
+                values: Vec::new(),
+            });
+        }
+    }
+
+    // The coefficients are transposed, so all of them have to be there.
+    let count = f_rest_indices.len();
+    let valid_count = (0..=3).any(|degree| {
+        SphericalHarmonicsOrder::order_for_degree(degree)
+            .is_some_and(|order| order.scalar_count() == count)
+    });
+    if !valid_count || f_rest_indices.iter().any(|&index| index >= count) {
+        return Err(SpzError::InvalidShPropertyCount {
+            count,
+            max_index: f_rest_indices.iter().max().copied().unwrap_or(0),
+        });
+    }
+    Ok((extra_attributes, count))
+}
+
+/// The spherical harmonics degree of `count` `f_rest` coefficients, which
+/// `check_vertex_properties` has checked is one of degree 0 to 3.
+fn sh_degree_for_count(count: usize) -> u8 {
+    (0..=3)
+        .find(|&degree| {
+            SphericalHarmonicsOrder::order_for_degree(degree)
+                .is_some_and(|order| order.scalar_count() == count)
+        })
+        .unwrap_or(0)
+}
+
+/// PLY has no standard field for the SPZ header flags, so they are carried as header comments.
+const ANTIALIASED_COMMENT: &str = "antialiased";
+
+/// Where `load_ply_stream` puts the value of a vertex property.
+#[derive(Clone, Copy)]
+enum Target {
+    Position(usize),
+    /// A quaternion component, in `x y z w` order.
+    Rotation(usize),
+    Scale(usize),
+    /// A float opacity, which is a logit.
+    Alpha,
+    Color(usize),
+    /// The RGB-interleaved index of an `f_rest` coefficient.
+    ShScalar(usize),
+    /// A point cloud color channel, and the value of full intensity.
+    PointColor(usize, f32),
+    /// An integer opacity, and the value of full opacity.
+    Opacity(f32),
+    /// The index of an extra attribute.
+    Extra(usize),
+    /// The normals.
+    Ignored,
+}
+
+/// The header as checked by `read_checked_header`.
+struct CheckedHeader {
+    bytes: Vec<u8>,
+    /// The binary `half` properties, which are passed to ply-rs as `ushort` and converted after
+    /// parsing.
+    half_properties: HashSet<String>,
+}
+
+/// ply-rs panics on header integers that don't fit in a `u64`, so the header is read and checked
+/// before it is handed to the parser. ply-rs also doesn't know the `half` type, so `half`
+/// properties are rewritten to types of the same size.
+fn read_checked_header<T: BufRead>(stream: &mut T) -> Result<CheckedHeader> {
+    let mut header = CheckedHeader {
+        bytes: Vec::new(),
+        half_properties: HashSet::new(),
+    };
+    let mut ascii = false;
+    loop {
+        let start = header.bytes.len();
+        if stream.read_until(b'\n', &mut header.bytes)? == 0 {
+            return Err(SpzError::PlyParse("missing end_header".to_string()));
+        }
+        let line = String::from_utf8_lossy(&header.bytes[start..]).into_owned();
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        match tokens.as_slice() {
+            ["end_header", ..] => return Ok(header),
+            ["comment", ..] | ["obj_info", ..] => continue,
+            ["format", format, ..] => ascii = *format == "ascii",
+            ["property", "half" | "float16", name] => {
+                // ASCII halves are written as decimals, which parse as floats.
+                let data_type = if ascii { "float" } else { "ushort" };
+                let ending = if line.ends_with("\r\n") { "\r\n" } else { "\n" };
+                header.bytes.truncate(start);
+                header
+                    .bytes
+                    .extend_from_slice(format!("property {data_type} {name}{ending}").as_bytes());
+                if !ascii {
+                    header.half_properties.insert(name.to_string());
+                }
+            }
+            _ => (),
+        }
+        // The grammar parses any run of digits as an integer, even inside a name.
+        if let Some(digits) = line
+            .split(|c: char| !c.is_ascii_digit())
+            .find(|digits| !digits.is_empty() && digits.parse::<u64>().is_err())
+        {
+            return Err(SpzError::PlyParse(format!(
+                "header value {} is too large",
+                digits
+            )));
+        }
+    }
+}
+
+/// Reads a gaussian splat PLY file. Vertex properties of any scalar type are converted to the
+/// `f32`s of the cloud, and unknown ones are kept as `GaussianCloud::extra_attributes`. Plain
+/// colored point clouds are converted with `GaussianCloud::from_points`, and SuperSplat
+/// compressed PLY files are recognised by their `chunk` element.
+pub fn load_ply_stream<T: BufRead>(stream: &mut T) -> Result<GaussianCloud> {
+    Ok(load_ply_stream_with_report(stream)?.0)
+}
+
+/// What was lost reading a PLY file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PlyReadReport {
+    /// The largest change to a coordinate from rounding it to the `f32` a `GaussianCloud` holds.
+    /// Non-zero for `double` (or large integer) positions that need more precision, as in
+    /// georeferenced scenes far from the origin.
+    pub position_rounding_error: f64,
+}
+
+/// Like `load_ply_stream`, but also reports the precision lost reading positions.
+pub fn load_ply_stream_with_report<T: BufRead>(
+    stream: &mut T,
+) -> Result<(GaussianCloud, PlyReadReport)> {
+    let CheckedHeader {
+        bytes,
+        half_properties,
+    } = read_checked_header(stream)?;
+    let stream = &mut std::io::Cursor::new(bytes).chain(stream);
+    let header = parser::Parser::<DefaultElement>::new()
+        .read_header(stream)
+        .map_err(|e| SpzError::PlyParse(e.to_string()))?;
+    let antialiased = header
+        .comments
+        .iter()
+        .any(|comment| comment.trim() == ANTIALIASED_COMMENT);
+    let mut report = PlyReadReport::default();
+    if header.elements.contains_key("chunk") {
+        let cloud = load_compressed_ply(stream, &header, antialiased)?;
+        return Ok((cloud, report));
+    }
+    let mut cloud = GaussianCloud::default();
+    let mut extra_attributes = Vec::new();
+    let mut point_cloud = false;
     for (_ignore_key, element) in &header.elements {
         match element.name.as_ref() {
             "vertex" => {
-                gaussian_list =
-                    gaussian_parser.read_payload_for_element(stream, element, &header)?;
+                let f_rest_count;
+                (extra_attributes, f_rest_count) = check_vertex_properties(element)?;
+                point_cloud = is_point_cloud(element);
+                cloud.sh_degree = sh_degree_for_count(f_rest_count);
+                let targets = element
+                    .properties
+                    .iter()
+                    .map(|(name, property)| {
+                        let is_half = half_properties.contains(name);
+                        // Halves are parsed as `ushort`s, but hold the value itself.
+                        let scale = match &property.data_type {
+                            PropertyType::Scalar(data_type) if !is_half => full_scale(data_type),
+                            _ => None,
+                        };
+                        let target = match (f_rest_index(name), color_channel(name), scale) {
+                            (Some(index), _, _) => {
+                                Target::ShScalar(f_rest_to_scalar_index(index, f_rest_count))
+                            }
+                            (None, Some(channel), scale) if point_cloud => {
+                                Target::PointColor(channel, scale.unwrap_or(1.0))
+                            }
+                            (None, None, Some(scale)) if name == "opacity" => {
+                                Target::Opacity(scale)
+                            }
+                            _ if is_gaussian_property(name) => {
+                                gaussian_target(name).unwrap_or(Target::Ignored)
+                            }
+                            _ => extra_attributes
+                                .iter()
+                                .position(|attribute| attribute.name == *name)
+                                .map_or(Target::Ignored, Target::Extra),
+                        };
+                        (target, is_half)
+                    })
+                    .collect::<Vec<_>>();
+                let default = UnpackedGaussian::default();
+                read_element(stream, element, header.encoding, |values| {
+                    cloud.positions.push(default.position);
+                    cloud.rotations.push(default.rotation);
+                    cloud.scales.push(default.scales);
+                    cloud.colors.push(default.color);
+                    cloud.alphas.push(default.alpha);
+                    let sh_start = cloud.spherical_harmonics.len();
+                    cloud
+                        .spherical_harmonics
+                        .resize(sh_start + f_rest_count, 0.0);
+                    for (&(target, is_half), &value) in targets.iter().zip(values) {
+                        let value = if is_half {
+                            half_to_float(value as u16) as f64
+                        } else {
+                            value
+                        };
+                        let v = value as f32;
+                        match target {
+                            Target::Position(axis) => {
+                                report.position_rounding_error =
+                                    report.position_rounding_error.max((value - v as f64).abs());
+                                cloud.positions.last_mut().unwrap()[axis] = v;
+                            }
+                            Target::Rotation(component) => {
+                                let rotation = cloud.rotations.last_mut().unwrap();
+                                match component {
+                                    0 => rotation.x = v,
+                                    1 => rotation.y = v,
+                                    2 => rotation.z = v,
+                                    _ => rotation.w = v,
+                                }
+                            }
+                            Target::Scale(axis) => cloud.scales.last_mut().unwrap()[axis] = v,
+                            Target::Alpha => *cloud.alphas.last_mut().unwrap() = v,
+                            Target::Color(channel) => cloud.colors.last_mut().unwrap()[channel] = v,
+                            Target::ShScalar(index) => {
+                                cloud.spherical_harmonics[sh_start + index] = v
+                            }
+                            Target::PointColor(channel, scale) => {
+                                cloud.colors.last_mut().unwrap()[channel] = v / scale
+                            }
+                            Target::Opacity(scale) => {
+                                *cloud.alphas.last_mut().unwrap() = opacity_logit(v, scale)
+                            }
+                            Target::Extra(index) => extra_attributes[index].values.push(value),
+                            Target::Ignored => (),
+                        }
+                    }
+                })?;
+                cloud.num_points = cloud.positions.len();
             }
-            _ => return Err(anyhow::anyhow!("unknown element")),
+            name => return Err(SpzError::UnknownPlyElement(name.to_string())),
+        }
+    }
+    for attribute in &mut extra_attributes {
+        if half_properties.contains(&attribute.name) {
+            attribute.data_type = ScalarType::Float;
+        }
+    }
+    if point_cloud {
+        cloud = GaussianCloud::from_points(&cloud.positions, &cloud.colors)?;
+    }
+    cloud.antialiased = antialiased;
+    cloud.extra_attributes = extra_attributes;
+    Ok((cloud, report))
+}
+
+#[derive(Debug, Clone)]
+pub struct PlyWriterOptions {
+    pub encoding: PlyEncoding,
+    /// The highest spherical harmonics degree to write, 0 to 3. Higher degree coefficients are
+    /// dropped.
+    pub max_sh_degree: u8,
+    /// Whether to write positions as `double`, as some tools expect for large georeferenced
+    /// scenes. A `GaussianCloud` holds positions as `f32`s, so this only widens them; see
+    /// `PlyReadReport::position_rounding_error` for what was lost reading `double` positions.
+    pub double_positions: bool,
+    /// Whether to write the SuperSplat compressed layout instead, which quantizes each gaussian
+    /// to 16 bytes plus a byte per spherical harmonics coefficient. It is always binary little
+    /// endian, `double_positions` doesn't apply, and extra attributes are dropped.
+    pub compressed: bool,
+}
+
+impl Default for PlyWriterOptions {
+    fn default() -> Self {
+        Self {
+            encoding: PlyEncoding::default(),
+            max_sh_degree: 3,
+            double_positions: false,
+            compressed: false,
         }
     }
-    Ok(gaussian_list)
+}
+
+/// Extra attributes become vertex properties, so their names must be unique and not clash with
+/// the gaussian's own properties.
+fn check_extra_attributes(cloud: &GaussianCloud) -> Result<()> {
+    let mut names = HashSet::new();
+    for attribute in &cloud.extra_attributes {
+        if is_gaussian_property(&attribute.name) || !names.insert(&attribute.name) {
+            return Err(SpzError::ExtraAttributeClash(attribute.name.clone()));
+        }
+    }
+    Ok(())
 }
 
 pub fn write_ply_stream<W: Write>(
-    gaussians: &Vec<UnpackedGaussian>,
+    cloud: &GaussianCloud,
     stream: &mut W,
-    encoding: &PlyEncoding,
+    options: &PlyWriterOptions,
 ) -> Result<()> {
+    cloud.validate()?;
+    let cloud = cloud.with_max_sh_degree(options.max_sh_degree)?;
+    if options.compressed {
+        return write_compressed_ply_stream(&cloud, stream);
+    }
+    check_extra_attributes(&cloud)?;
     let mut ply = {
         let mut ply = Ply::<DefaultElement>::new();
 
-        ply.header.encoding = match encoding {
+        if cloud.antialiased {
+            ply.header.comments.push(ANTIALIASED_COMMENT.to_string());
+        }
+
+        ply.header.encoding = match options.encoding {
             PlyEncoding::Ascii => Encoding::Ascii,
             PlyEncoding::BinaryBigEndian => Encoding::BinaryBigEndian,
             PlyEncoding::BinaryLittleEndian => Encoding::BinaryLittleEndian,
         };
 
         let mut element = ElementDef::new("vertex".to_string());
-        element.properties.add(PropertyDef::new(
-            "x".to_string(),
-            PropertyType::Scalar(ScalarType::Float),
-        ));
-        element.properties.add(PropertyDef::new(
-            "y".to_string(),
-            PropertyType::Scalar(ScalarType::Float),
-        ));
-        element.properties.add(PropertyDef::new(
-            "z".to_string(),
-            PropertyType::Scalar(ScalarType::Float),
-        ));
+        let position_type = if options.double_positions {
+            ScalarType::Double
+        } else {
+            ScalarType::Float
+        };
+        for name in ["x", "y", "z"] {
+            element.properties.add(PropertyDef::new(
+                name.to_string(),
+                PropertyType::Scalar(position_type.clone()),
+            ));
+        }
         element.properties.add(PropertyDef::new(
             "rot_0".to_string(),
             PropertyType::Scalar(ScalarType::Float),
@@ -143,39 +662,57 @@ pub fn write_ply_stream<W: Write>(
             "f_dc_2".to_string(),
             PropertyType::Scalar(ScalarType::Float),
         ));
-        for i in 0..gaussians[0].spherical_harmonics.order().scalar_count() {
+        for i in 0..cloud.sh_scalar_count() {
             element.properties.add(PropertyDef::new(
                 format!("f_rest_{}", i).to_string(),
                 PropertyType::Scalar(ScalarType::Float),
             ));
         }
+        for attribute in &cloud.extra_attributes {
+            element.properties.add(PropertyDef::new(
+                attribute.name.clone(),
+                PropertyType::Scalar(attribute.data_type.clone()),
+            ));
+        }
 
         ply.header.elements.add(element);
 
         let mut records = Vec::new();
 
-        for gaussian in gaussians {
+        for i in 0..cloud.num_points {
+            let position = cloud.positions[i];
+            let rotation = cloud.rotations[i];
+            let scales = cloud.scales[i];
+            let color = cloud.colors[i];
             let mut record = DefaultElement::new();
 
-            record.insert("x".to_string(), Property::Float(gaussian.position.x));
-            record.insert("y".to_string(), Property::Float(gaussian.position.y));
-            record.insert("z".to_string(), Property::Float(gaussian.position.z));
-            record.insert("rot_0".to_string(), Property::Float(gaussian.rotation.x));
-            record.insert("rot_1".to_string(), Property::Float(gaussian.rotation.y));
-            record.insert("rot_2".to_string(), Property::Float(gaussian.rotation.z));
-            record.insert("rot_3".to_string(), Property::Float(gaussian.rotation.w));
-            record.insert("scale_0".to_string(), Property::Float(gaussian.scales.x));
-            record.insert("scale_1".to_string(), Property::Float(gaussian.scales.y));
-            record.insert("scale_2".to_string(), Property::Float(gaussian.scales.z));
-            record.insert("opacity".to_string(), Property::Float(gaussian.alpha));
+            for (name, v) in ["x", "y", "z"].into_iter().zip(position) {
+                record.insert(name.to_string(), scalar_property(v as f64, &position_type));
+            }
+            record.insert("rot_0".to_string(), Property::Float(rotation.x));
+            record.insert("rot_1".to_string(), Property::Float(rotation.y));
+            record.insert("rot_2".to_string(), Property::Float(rotation.z));
+            record.insert("rot_3".to_string(), Property::Float(rotation.w));
+            record.insert("scale_0".to_string(), Property::Float(scales.x));
+            record.insert("scale_1".to_string(), Property::Float(scales.y));
+            record.insert("scale_2".to_string(), Property::Float(scales.z));
+            record.insert("opacity".to_string(), Property::Float(cloud.alphas[i]));
             record.insert("nx".to_string(), Property::Float(0.0));
             record.insert("ny".to_string(), Property::Float(0.0));
             record.insert("nz".to_string(), Property::Float(0.0));
-            record.insert("f_dc_0".to_string(), Property::Float(gaussian.color.x));
-            record.insert("f_dc_1".to_string(), Property::Float(gaussian.color.y));
-            record.insert("f_dc_2".to_string(), Property::Float(gaussian.color.z));
-            for (i, v) in gaussian.spherical_harmonics.scalars().iter().enumerate() {
-                record.insert(format!("f_rest_{}", i).to_string(), Property::Float(*v));
+            record.insert("f_dc_0".to_string(), Property::Float(color.x));
+            record.insert("f_dc_1".to_string(), Property::Float(color.y));
+            record.insert("f_dc_2".to_string(), Property::Float(color.z));
+            let sh_scalars = cloud.sh_scalars(i).unwrap_or_default();
+            for index in 0..sh_scalars.len() {
+                let v = sh_scalars[f_rest_to_scalar_index(index, sh_scalars.len())];
+                record.insert(format!("f_rest_{}", index), Property::Float(v));
+            }
+            for attribute in &cloud.extra_attributes {
+                record.insert(
+                    attribute.name.clone(),
+                    scalar_property(attribute.values[i], &attribute.data_type),
+                );
             }
             records.push(record)
         }
@@ -192,27 +729,410 @@ pub fn write_ply_stream<W: Write>(
     Ok(())
 }
 
-pub fn load_ply(path: &Path) -> Result<Vec<UnpackedGaussian>> {
+// ---- SuperSplat compressed PLY ----
+
+/// The number of gaussians sharing the quantization bounds of a `chunk` element.
+const COMPRESSED_CHUNK_SIZE: usize = 256;
+
+/// The position and log-scale bounds of a chunk, each as a minimum then a maximum.
+const COMPRESSED_CHUNK_PROPERTIES: [&str; 12] = [
+    "min_x",
+    "min_y",
+    "min_z",
+    "max_x",
+    "max_y",
+    "max_z",
+    "min_scale_x",
+    "min_scale_y",
+    "min_scale_z",
+    "max_scale_x",
+    "max_scale_y",
+    "max_scale_z",
+];
+
+/// The linear color bounds of a chunk. Older files don't have them, and store colors unscaled.
+const COMPRESSED_CHUNK_COLOR_PROPERTIES: [&str; 6] =
+    ["min_r", "min_g", "min_b", "max_r", "max_g", "max_b"];
+
+const COMPRESSED_VERTEX_PROPERTIES: [&str; 4] = [
+    "packed_position",
+    "packed_rotation",
+    "packed_scale",
+    "packed_color",
+];
+
+/// The positions of the named properties in `element`.
+fn compressed_columns(element: &ElementDef, names: &[&str]) -> Result<Vec<usize>> {
+    names
+        .iter()
+        .map(|name| {
+            element
+                .properties
+                .keys()
+                .position(|key| key == name)
+                .ok_or_else(|| SpzError::MissingPlyProperty {
+                    element: element.name.clone(),
+                    name: name.to_string(),
+                })
+        })
+        .collect()
+}
+
+/// The minimum and maximum stored in six consecutive `columns` of a chunk.
+fn chunk_bounds(chunk: &[f64], columns: &[usize]) -> (Vec3<f32>, Vec3<f32>) {
+    let value = |index: usize| chunk[columns[index]] as f32;
+    (
+        Vec3::new(value(0), value(1), value(2)),
+        Vec3::new(value(3), value(4), value(5)),
+    )
+}
+
+fn bounds(values: &[Vec3<f32>]) -> (Vec3<f32>, Vec3<f32>) {
+    values.iter().fold(
+        (
+            Vec3::broadcast(f32::INFINITY),
+            Vec3::broadcast(f32::NEG_INFINITY),
+        ),
+        |(min, max), &v| (Vec3::partial_min(min, v), Vec3::partial_max(max, v)),
+    )
+}
+
+/// Maps `v` from `min..=max` to `0.0..=1.0`, per component. Empty ranges map to 0.
+fn normalize_in(v: Vec3<f32>, min: Vec3<f32>, max: Vec3<f32>) -> Vec3<f32> {
+    Vec3::from(std::array::from_fn::<f32, 3, _>(|i| {
+        if max[i] > min[i] {
+            (v[i] - min[i]) / (max[i] - min[i])
+        } else {
+            0.0
+        }
+    }))
+}
+
+fn unpack_unorm(value: u32, bits: u32) -> f32 {
+    let max = (1 << bits) - 1;
+    (value & max) as f32 / max as f32
+}
+
+fn pack_unorm(value: f32, bits: u32) -> u32 {
+    let max = ((1 << bits) - 1) as f32;
+    (value * max + 0.5).floor().clamp(0.0, max) as u32
+}
+
+/// Unpacks three unit values of 11, 10 and 11 bits.
+fn unpack_111011(value: u32) -> Vec3<f32> {
+    Vec3::new(
+        unpack_unorm(value >> 21, 11),
+        unpack_unorm(value >> 11, 10),
+        unpack_unorm(value, 11),
+    )
+}
+
+fn pack_111011(v: Vec3<f32>) -> u32 {
+    pack_unorm(v.x, 11) << 21 | pack_unorm(v.y, 10) << 11 | pack_unorm(v.z, 11)
+}
+
+/// Unpacks a color and an opacity of 8 bits each.
+fn unpack_8888(value: u32) -> (Vec3<f32>, f32) {
+    (
+        Vec3::new(
+            unpack_unorm(value >> 24, 8),
+            unpack_unorm(value >> 16, 8),
+            unpack_unorm(value >> 8, 8),
+        ),
+        unpack_unorm(value, 8),
+    )
+}
+
+fn pack_8888(color: Vec3<f32>, opacity: f32) -> u32 {
+    pack_unorm(color.x, 8) << 24
+        | pack_unorm(color.y, 8) << 16
+        | pack_unorm(color.z, 8) << 8
+        | pack_unorm(opacity, 8)
+}
+
+/// Rotations are stored as the smallest three components, in `rot_0` to `rot_3` order, of 10 bits
+/// each, after 2 bits for the index of the largest, which is recomputed.
+fn unpack_rotation(value: u32) -> Quaternion<f32> {
+    let mut components = [20, 10, 0]
+        .map(|shift| (unpack_unorm(value >> shift, 10) - 0.5) * SQRT_2)
+        .to_vec();
+    let largest = (1.0 - components.iter().map(|c| c * c).sum::<f32>())
+        .max(0.0)
+        .sqrt();
+    components.insert((value >> 30) as usize, largest);
+    Quaternion::from_xyzw(components[0], components[1], components[2], components[3])
+}
+
+fn pack_rotation(rotation: Quaternion<f32>) -> u32 {
+    let q = rotation.normalized();
+    let mut components = [q.x, q.y, q.z, q.w];
+    let largest = (0..4)
+        .max_by(|&a, &b| components[a].abs().total_cmp(&components[b].abs()))
+        .unwrap_or(0);
+    // q and -q are the same rotation, so the dropped component can be made positive.
+    if components[largest] < 0.0 {
+        components = components.map(|c| -c);
+    }
+    components
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| index != largest)
+        .fold(largest as u32, |packed, (_, &c)| {
+            packed << 10 | pack_unorm(c * FRAC_1_SQRT_2 + 0.5, 10)
+        })
+}
+
+/// Spherical harmonics coefficients are stored as bytes over `-4.0..4.0`.
+fn unpack_sh(value: u8) -> f32 {
+    let unit = match value {
+        0 => 0.0,
+        255 => 1.0,
+        _ => (value as f32 + 0.5) / 256.0,
+    };
+    (unit - 0.5) * 8.0
+}
+
+fn pack_sh(value: f32) -> u8 {
+    ((value / 8.0 + 0.5) * 256.0).floor().clamp(0.0, 255.0) as u8
+}
+
+/// Reads the `chunk`, `vertex` and optional `sh` elements of a SuperSplat compressed PLY file.
+fn load_compressed_ply<T: BufRead>(
+    stream: &mut T,
+    header: &ply::Header,
+    antialiased: bool,
+) -> Result<GaussianCloud> {
+    let mut read = |element: &ElementDef| PlyRows::read(stream, element, header.encoding);
+    let mut chunks = PlyRows::default();
+    let mut chunk_columns = Vec::new();
+    let mut color_columns = None;
+    let mut vertices = None;
+    let mut sh = None;
+    for (_ignore_key, element) in &header.elements {
+        match element.name.as_ref() {
+            "chunk" => {
+                chunk_columns = compressed_columns(element, &COMPRESSED_CHUNK_PROPERTIES)?;
+                color_columns =
+                    compressed_columns(element, &COMPRESSED_CHUNK_COLOR_PROPERTIES).ok();
+                chunks = read(element)?;
+            }
+            "vertex" => {
+                let columns = compressed_columns(element, &COMPRESSED_VERTEX_PROPERTIES)?;
+                vertices = Some((columns, read(element)?));
+            }
+            "sh" => {
+                let (_, f_rest_count) = check_vertex_properties(element)?;
+                let targets = element
+                    .properties
+                    .keys()
+                    .map(|name| {
+                        f_rest_index(name).map(|index| f_rest_to_scalar_index(index, f_rest_count))
+                    })
+                    .collect::<Vec<_>>();
+                sh = Some((f_rest_count, targets, read(element)?));
+            }
+            name => return Err(SpzError::UnknownPlyElement(name.to_string())),
+        }
+    }
+    let Some((vertex_columns, vertices)) = vertices else {
+        return Err(SpzError::MissingPlyElement("vertex".to_string()));
+    };
+    if chunks.len < vertices.len.div_ceil(COMPRESSED_CHUNK_SIZE) {
+        return Err(SpzError::PlyElementCount {
+            element: "chunk".to_string(),
+            expected: vertices.len.div_ceil(COMPRESSED_CHUNK_SIZE),
+            actual: chunks.len,
+        });
+    }
+    let (f_rest_count, sh_targets, sh_rows) = sh.unwrap_or_default();
+    if f_rest_count > 0 && sh_rows.len != vertices.len {
+        return Err(SpzError::PlyElementCount {
+            element: "sh".to_string(),
+            expected: vertices.len,
+            actual: sh_rows.len,
+        });
+    }
+
+    let mut cloud = GaussianCloud {
+        num_points: vertices.len,
+        sh_degree: sh_degree_for_count(f_rest_count),
+        antialiased,
+        ..Default::default()
+    };
+    let mut sh_scalars = vec![0.0; f_rest_count];
+    for index in 0..vertices.len {
+        let chunk = chunks.row(index / COMPRESSED_CHUNK_SIZE);
+        let vertex = vertices.row(index);
+        let packed = |column: usize| vertex[vertex_columns[column]] as u32;
+        let lerp = |(min, max): (Vec3<f32>, Vec3<f32>), t: Vec3<f32>| min + (max - min) * t;
+
+        let position = unpack_111011(packed(0));
+        cloud
+            .positions
+            .push(lerp(chunk_bounds(chunk, &chunk_columns[..6]), position));
+        cloud.rotations.push(unpack_rotation(packed(1)));
+        let scale = unpack_111011(packed(2));
+        cloud
+            .scales
+            .push(lerp(chunk_bounds(chunk, &chunk_columns[6..]), scale));
+        let (color, opacity) = unpack_8888(packed(3));
+        let color = match &color_columns {
+            Some(columns) => lerp(chunk_bounds(chunk, columns), color),
+            None => color,
+        };
+        cloud.colors.push(color.map(linear_to_sph0));
+        // Fully transparent or opaque gaussians would have infinite logits.
+        cloud.alphas.push(inv_sigmoid(opacity).clamp(-40.0, 40.0));
+
+        if f_rest_count > 0 {
+            for (&target, &value) in sh_targets.iter().zip(sh_rows.row(index)) {
+                if let Some(target) = target {
+                    sh_scalars[target] = unpack_sh(value as u8);
+                }
+            }
+            cloud.spherical_harmonics.extend_from_slice(&sh_scalars);
+        }
+    }
+    Ok(cloud)
+}
+
+fn write_compressed_ply_stream<W: Write>(cloud: &GaussianCloud, stream: &mut W) -> Result<()> {
+    let mut ply = Ply::<DefaultElement>::new();
+    if cloud.antialiased {
+        ply.header.comments.push(ANTIALIASED_COMMENT.to_string());
+    }
+    ply.header.encoding = Encoding::BinaryLittleEndian;
+
+    let chunk_names = COMPRESSED_CHUNK_PROPERTIES
+        .iter()
+        .chain(&COMPRESSED_CHUNK_COLOR_PROPERTIES);
+    let mut element = ElementDef::new("chunk".to_string());
+    for name in chunk_names.clone() {
+        element.properties.add(PropertyDef::new(
+            name.to_string(),
+            PropertyType::Scalar(ScalarType::Float),
+        ));
+    }
+    ply.header.elements.add(element);
+    let mut element = ElementDef::new("vertex".to_string());
+    for name in COMPRESSED_VERTEX_PROPERTIES {
+        element.properties.add(PropertyDef::new(
+            name.to_string(),
+            PropertyType::Scalar(ScalarType::UInt),
+        ));
+    }
+    ply.header.elements.add(element);
+    let sh_count = cloud.sh_scalar_count();
+    if sh_count > 0 {
+        let mut element = ElementDef::new("sh".to_string());
+        for i in 0..sh_count {
+            element.properties.add(PropertyDef::new(
+                format!("f_rest_{}", i),
+                PropertyType::Scalar(ScalarType::UChar),
+            ));
+        }
+        ply.header.elements.add(element);
+    }
+
+    let colors = cloud
+        .colors
+        .iter()
+        .map(|c| c.map(sph0_to_linear))
+        .collect::<Vec<_>>();
+    let mut chunks = Vec::new();
+    let mut vertices = Vec::with_capacity(cloud.num_points);
+    let mut sh_rows = Vec::new();
+    for start in (0..cloud.num_points).step_by(COMPRESSED_CHUNK_SIZE) {
+        let range = start..(start + COMPRESSED_CHUNK_SIZE).min(cloud.num_points);
+        let (position_min, position_max) = bounds(&cloud.positions[range.clone()]);
+        let (scale_min, scale_max) = bounds(&cloud.scales[range.clone()]);
+        let (color_min, color_max) = bounds(&colors[range.clone()]);
+
+        let mut chunk = DefaultElement::new();
+        let values = [
+            position_min,
+            position_max,
+            scale_min,
+            scale_max,
+            color_min,
+            color_max,
+        ];
+        for (name, v) in chunk_names
+            .clone()
+            .zip(values.iter().flat_map(|v| v.iter()))
+        {
+            chunk.insert(name.to_string(), Property::Float(*v));
+        }
+        chunks.push(chunk);
+
+        for i in range {
+            let packed = [
+                pack_111011(normalize_in(cloud.positions[i], position_min, position_max)),
+                pack_rotation(cloud.rotations[i]),
+                pack_111011(normalize_in(cloud.scales[i], scale_min, scale_max)),
+                pack_8888(
+                    normalize_in(colors[i], color_min, color_max),
+                    sigmoid(cloud.alphas[i]),
+                ),
+            ];
+            let mut vertex = DefaultElement::new();
+            for (name, v) in COMPRESSED_VERTEX_PROPERTIES.into_iter().zip(packed) {
+                vertex.insert(name.to_string(), Property::UInt(v));
+            }
+            vertices.push(vertex);
+
+            if sh_count > 0 {
+                let sh_scalars = cloud.sh_scalars(i).unwrap_or_default();
+                let mut record = DefaultElement::new();
+                for index in 0..sh_count {
+                    let v = sh_scalars[f_rest_to_scalar_index(index, sh_count)];
+                    record.insert(format!("f_rest_{}", index), Property::UChar(pack_sh(v)));
+                }
+                sh_rows.push(record);
+            }
+        }
+    }
+    ply.payload.insert("chunk".to_string(), chunks);
+    ply.payload.insert("vertex".to_string(), vertices);
+    if sh_count > 0 {
+        ply.payload.insert("sh".to_string(), sh_rows);
+    }
+
+    let w = Writer::new();
+    w.write_ply(stream, &mut ply)?;
+    Ok(())
+}
+
+pub fn load_ply(path: &Path) -> Result<GaussianCloud> {
+    Ok(load_ply_with_report(path)?.0)
+}
+
+pub fn load_ply_with_report(path: &Path) -> Result<(GaussianCloud, PlyReadReport)> {
     let file = std::fs::File::open(path)?;
     let mut stream = std::io::BufReader::new(file);
-    load_ply_stream(&mut stream)
+    load_ply_stream_with_report(&mut stream)
 }
 
-pub fn write_ply(
-    gaussians: &Vec<UnpackedGaussian>,
-    path: &Path,
-    encoding: &PlyEncoding,
-) -> Result<()> {
+pub fn write_ply(cloud: &GaussianCloud, path: &Path, options: &PlyWriterOptions) -> Result<()> {
     let mut file = std::fs::File::create(path)?;
-    write_ply_stream(gaussians, &mut file, encoding)
+    write_ply_stream(cloud, &mut file, options)
 }
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::spherical_harmonics::SphericalHarmonics;
+    use crate::support::{inv_sigmoid, linear_to_sph0};
     use vek::{Quaternion, Vec3};
 
+    fn ascii() -> PlyWriterOptions {
+        PlyWriterOptions {
+            encoding: PlyEncoding::Ascii,
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_ply_load_save() {
         // TODO: Test with spherical harmonics
@@ -237,7 +1157,9 @@ end_header
 100.0 200.0 -100.0 1.0 0.5 0.25 0.95 1.0 -1.0 1.0 0.333 0.333 0.333 1.0
     "#;
         let mut stream = std::io::BufReader::new(ply.as_bytes());
-        let gaussians = load_ply_stream(&mut stream).unwrap();
+        let cloud = load_ply_stream(&mut stream).unwrap();
+        assert!(!cloud.antialiased);
+        let gaussians = cloud.to_gaussians();
         assert_eq!(gaussians.len(), 1);
         assert_eq!(gaussians[0].position, Vec3::new(100.0, 200.0, -100.0));
         assert_eq!(gaussians[0].color, Vec3::new(1.0, 0.5, 0.25));
@@ -249,12 +1171,406 @@ end_header
         );
         assert_eq!(gaussians[0].spherical_harmonics.order().index(), 0);
         let mut output = Vec::new();
-        write_ply_stream(&gaussians, &mut output, &PlyEncoding::Ascii).unwrap();
+        write_ply_stream(&cloud, &mut output, &ascii()).unwrap();
         let mut stream = std::io::BufReader::new(output.as_slice());
-        let result = load_ply_stream(&mut stream).unwrap();
+        let result = load_ply_stream(&mut stream).unwrap().to_gaussians();
         assert!(gaussians.len() == result.len());
         assert_eq!(gaussians[0], result[0]);
     }
+
+    #[test]
+    fn test_ply_antialiased() {
+        let mut cloud = GaussianCloud::from_gaussians(&[UnpackedGaussian::default()]).unwrap();
+        cloud.antialiased = true;
+        let mut output = Vec::new();
+        write_ply_stream(&cloud, &mut output, &ascii()).unwrap();
+        let mut stream = std::io::BufReader::new(output.as_slice());
+        let result = load_ply_stream(&mut stream).unwrap();
+        assert!(result.antialiased);
+    }
+
+    #[test]
+    fn test_ply_max_sh_degree() {
+        let cloud = GaussianCloud::from_gaussians(&[UnpackedGaussian {
+            spherical_harmonics: SphericalHarmonics::Order3([Vec3::broadcast(0.5); 15]),
+            ..Default::default()
+        }])
+        .unwrap();
+        let mut output = Vec::new();
+        let options = PlyWriterOptions {
+            max_sh_degree: 1,
+            ..ascii()
+        };
+        write_ply_stream(&cloud, &mut output, &options).unwrap();
+        let mut stream = std::io::BufReader::new(output.as_slice());
+        let result = load_ply_stream(&mut stream).unwrap();
+        assert_eq!(result.sh_degree, 1);
+        assert_eq!(result.spherical_harmonics, vec![0.5; 9]);
+    }
+
+    #[test]
+    fn test_ply_extra_attributes() {
+        let ply = r#"ply
+format ascii 1.0
+element vertex 2
+property float x
+property int semantic_id
+property float y
+property float z
+property uchar flag
+property double confidence
+property float f_rest_x
+end_header
+1.0 -7 2.0 3.0 255 0.125 0.5
+4.0 12 5.0 6.0 0 0.75 -0.5
+"#;
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        let cloud = load_ply_stream(&mut stream).unwrap();
+        assert_eq!(cloud.positions[1], Vec3::new(4.0, 5.0, 6.0));
+        let names = cloud
+            .extra_attributes
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["semantic_id", "flag", "confidence", "f_rest_x"]);
+        let semantic_id = cloud.extra_attribute("semantic_id").unwrap();
+        assert_eq!(semantic_id.data_type, ScalarType::Int);
+        assert_eq!(semantic_id.values, [-7.0, 12.0]);
+        assert_eq!(cloud.extra_attribute("flag").unwrap().values, [255.0, 0.0]);
+        assert_eq!(
+            cloud.extra_attribute("confidence").unwrap().values,
+            [0.125, 0.75]
+        );
+
+        let swapped = cloud.permute(&[1, 0]).unwrap();
+        assert_eq!(
+            swapped.extra_attribute("semantic_id").unwrap().values,
+            [12.0, -7.0]
+        );
+
+        for encoding in [
+            PlyEncoding::Ascii,
+            PlyEncoding::BinaryBigEndian,
+            PlyEncoding::BinaryLittleEndian,
+        ] {
+            let mut output = Vec::new();
+            let options = PlyWriterOptions {
+                encoding,
+                ..Default::default()
+            };
+            write_ply_stream(&cloud, &mut output, &options).unwrap();
+            let mut stream = std::io::BufReader::new(output.as_slice());
+            assert_eq!(load_ply_stream(&mut stream).unwrap(), cloud);
+        }
+
+        let mut clashing = cloud.clone();
+        clashing.extra_attributes[0].name = "opacity".to_string();
+        assert!(matches!(
+            write_ply_stream(&clashing, &mut Vec::new(), &ascii()),
+            Err(SpzError::ExtraAttributeClash(name)) if name == "opacity"
+        ));
+    }
+
+    #[test]
+    fn test_ply_scalar_types() {
+        let mut ply = b"ply
+format binary_little_endian 1.0
+element vertex 1
+property double x
+property double y
+property double z
+property uchar opacity
+property half scale_0
+property short f_dc_0
+property half confidence
+end_header
+"
+        .to_vec();
+        for v in [1.5f64, -2.0, 1e6] {
+            ply.extend_from_slice(&v.to_le_bytes());
+        }
+        ply.push(200);
+        ply.extend_from_slice(&0xc000u16.to_le_bytes());
+        ply.extend_from_slice(&(-3i16).to_le_bytes());
+        ply.extend_from_slice(&0x3c00u16.to_le_bytes());
+        let mut stream = std::io::BufReader::new(ply.as_slice());
+        let cloud = load_ply_stream(&mut stream).unwrap();
+        let gaussian = cloud.gaussian(0).unwrap();
+        assert_eq!(gaussian.position, Vec3::new(1.5, -2.0, 1e6));
+        assert_eq!(gaussian.alpha, inv_sigmoid(200.0 / 255.0));
+        assert_eq!(gaussian.scales.x, -2.0);
+        assert_eq!(gaussian.color.x, -3.0);
+        let confidence = cloud.extra_attribute("confidence").unwrap();
+        assert_eq!(confidence.data_type, ScalarType::Float);
+        assert_eq!(confidence.values, [1.0]);
+
+        let ply =
+            "ply\nformat ascii 1.0\nelement vertex 2\nproperty uchar opacity\nend_header\n0\n255\n";
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        let alphas = load_ply_stream(&mut stream).unwrap().alphas;
+        assert!(alphas[0] < -10.0 && alphas[1] > 10.0);
+
+        let ply = "ply\nformat ascii 1.0\nelement vertex 1\nproperty half x\nend_header\n0.5\n";
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        assert_eq!(load_ply_stream(&mut stream).unwrap().positions[0].x, 0.5);
+
+        let mut output = Vec::new();
+        let options = PlyWriterOptions {
+            double_positions: true,
+            ..ascii()
+        };
+        write_ply_stream(&cloud, &mut output, &options).unwrap();
+        assert!(String::from_utf8_lossy(&output).contains("property double x"));
+        let mut stream = std::io::BufReader::new(output.as_slice());
+        let (result, report) = load_ply_stream_with_report(&mut stream).unwrap();
+        assert_eq!(result, cloud);
+        assert_eq!(report.position_rounding_error, 0.0);
+
+        // Around 1e6 an f32 only has a resolution of 1/16.
+        let ply =
+            "ply\nformat ascii 1.0\nelement vertex 1\nproperty double x\nend_header\n1000000.03\n";
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        let (result, report) = load_ply_stream_with_report(&mut stream).unwrap();
+        assert_eq!(result.positions[0].x, 1e6);
+        assert!((report.position_rounding_error - 0.03).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_ply_channel_major_sh() {
+        // f_rest_<i> holds i / 100: red coefficients first, then green, then blue.
+        let f_rest = (0..45).map(|i| i as f32 / 100.0).collect::<Vec<_>>();
+        let mut ply = "ply\nformat ascii 1.0\nelement vertex 1\n".to_string();
+        for i in 0..45 {
+            ply += &format!("property float f_rest_{i}\n");
+        }
+        ply += "end_header\n";
+        ply += &f_rest
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        ply += "\n";
+
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        let cloud = load_ply_stream(&mut stream).unwrap();
+        assert_eq!(cloud.sh_degree, 3);
+        let SphericalHarmonics::Order3(coefficients) =
+            cloud.gaussian(0).unwrap().spherical_harmonics
+        else {
+            panic!("expected degree 3");
+        };
+        for (i, coefficient) in coefficients.iter().enumerate() {
+            assert_eq!(
+                *coefficient,
+                Vec3::new(f_rest[i], f_rest[15 + i], f_rest[30 + i])
+            );
+        }
+
+        // SPZ stores the coefficients RGB-interleaved.
+        let options = crate::spz_writer::SPZWriterOptions {
+            sh1_bits: 8,
+            sh_rest_bits: 8,
+            ..Default::default()
+        };
+        let mut spz = Vec::new();
+        crate::spz_format::write_spz_to_stream(&cloud, &mut spz, options).unwrap();
+        let decoded = crate::spz_reader::SPZReader::new_from_slice(
+            &spz,
+            crate::spz_reader::SPZReaderOptions::default(),
+        )
+        .read()
+        .unwrap();
+        for (a, b) in decoded
+            .spherical_harmonics
+            .iter()
+            .zip(&cloud.spherical_harmonics)
+        {
+            assert!((a - b).abs() < 0.004, "{a} != {b}");
+        }
+
+        // Written back in the original order.
+        let mut output = Vec::new();
+        write_ply_stream(&cloud, &mut output, &ascii()).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let values = output
+            .lines()
+            .last()
+            .unwrap()
+            .split_whitespace()
+            .map(|v| v.parse::<f32>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(values[values.len() - 45..], f_rest);
+    }
+
+    #[test]
+    fn test_ply_point_cloud() {
+        let ply = "ply
+format ascii 1.0
+element vertex 3
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+property uchar green
+property uchar blue
+property float intensity
+end_header
+0 0 0 0 0 1 255 0 51 0.5
+1 0 0 0 0 1 0 255 0 0.25
+0 2 0 0 0 1 0 0 255 1
+";
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        let cloud = load_ply_stream(&mut stream).unwrap();
+        assert_eq!(cloud.len(), 3);
+        assert_eq!(cloud.sh_degree, 0);
+        assert_eq!(
+            cloud.colors[0],
+            Vec3::new(
+                linear_to_sph0(1.0),
+                linear_to_sph0(0.0),
+                linear_to_sph0(0.2)
+            )
+        );
+        assert_eq!(cloud.alphas, vec![inv_sigmoid(0.9); 3]);
+        assert_eq!(cloud.scales[0], Vec3::broadcast(0.5 * 2.5f32.ln()));
+        assert_eq!(cloud.scales[1], Vec3::broadcast(0.5 * 3.0f32.ln()));
+        assert!(cloud.rotations.iter().all(|r| *r == Quaternion::identity()));
+        let intensity = cloud.extra_attribute("intensity").unwrap();
+        assert_eq!(intensity.values, [0.5, 0.25, 1.0]);
+        assert!(cloud.extra_attribute("red").is_none());
+        cloud.validate().unwrap();
+    }
+
+    #[test]
+    fn test_ply_compressed() {
+        let gaussians = (0..300)
+            .map(|i| {
+                let t = i as f32 / 300.0;
+                UnpackedGaussian {
+                    position: Vec3::new(t * 10.0, -t, (t * 7.0).sin()),
+                    rotation: Quaternion::from_xyzw(1.0, t, -0.5, t * 2.0 - 1.0).normalized(),
+                    scales: Vec3::new(-4.0 - t, -3.0, t - 5.0),
+                    color: Vec3::new(t, -t, 0.25),
+                    alpha: t * 8.0 - 4.0,
+                    spherical_harmonics: SphericalHarmonics::Order1([
+                        Vec3::new(t - 0.5, 0.25, -0.125),
+                        Vec3::broadcast(0.0),
+                        Vec3::new(1.0, -1.0, t),
+                    ]),
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+        cloud.antialiased = true;
+        let mut output = Vec::new();
+        let options = PlyWriterOptions {
+            compressed: true,
+            ..Default::default()
+        };
+        write_ply_stream(&cloud, &mut output, &options).unwrap();
+        // 2 chunks of 18 floats, then 16 bytes per gaussian and a byte per SH coefficient.
+        let header_end = output
+            .windows(11)
+            .position(|w| w == b"end_header\n")
+            .unwrap()
+            + 11;
+        assert_eq!(output.len() - header_end, 2 * 18 * 4 + 300 * (16 + 9));
+
+        let mut stream = std::io::BufReader::new(output.as_slice());
+        let result = load_ply_stream(&mut stream).unwrap();
+        assert_eq!(result.len(), 300);
+        assert_eq!(result.sh_degree, 1);
+        assert!(result.antialiased);
+        for i in 0..300 {
+            let (expected, actual) = (cloud.gaussian(i).unwrap(), result.gaussian(i).unwrap());
+            assert!((expected.position - actual.position).magnitude() < 0.01);
+            assert!(expected.rotation.dot(actual.rotation).abs() > 0.999);
+            assert!((expected.scales - actual.scales).magnitude() < 0.01);
+            let linear = |c: Vec3<f32>| c.map(sph0_to_linear);
+            assert!((linear(expected.color) - linear(actual.color)).magnitude() < 0.01);
+            assert!((sigmoid(expected.alpha) - sigmoid(actual.alpha)).abs() < 0.51 / 255.0);
+            for (e, a) in cloud
+                .sh_scalars(i)
+                .unwrap()
+                .iter()
+                .zip(result.sh_scalars(i).unwrap())
+            {
+                assert!((e - a).abs() <= 8.0 / 256.0);
+            }
+        }
+
+        // The largest rotation component is recomputed, whatever its position.
+        for q in [
+            Quaternion::from_xyzw(0.0, 0.0, 1.0, 0.0),
+            Quaternion::from_xyzw(-0.5, 0.5, -0.5, 0.5),
+            Quaternion::from_xyzw(0.1, 0.2, 0.3, -0.9).normalized(),
+        ] {
+            assert!(unpack_rotation(pack_rotation(q)).dot(q).abs() > 0.999);
+        }
+
+        let ply = "ply\nformat ascii 1.0\nelement chunk 0\nproperty float min_x\nend_header\n";
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        assert!(matches!(
+            load_ply_stream(&mut stream),
+            Err(SpzError::MissingPlyProperty { element, name }) if element == "chunk" && name == "min_y"
+        ));
+    }
+
+    #[test]
+    fn test_ply_malformed() {
+        let load = |property: &str| {
+            let ply = format!(
+                "ply\nformat ascii 1.0\nelement vertex 1\nproperty {property}\nend_header\n1.0\n"
+            );
+            load_ply_stream(&mut std::io::BufReader::new(ply.as_bytes()))
+        };
+        assert!(matches!(
+            load("float f_rest_45"),
+            Err(SpzError::InvalidShPropertyCount {
+                count: 1,
+                max_index: 45
+            })
+        ));
+        for property in ["list uchar int red", "list uchar float x"] {
+            assert!(
+                matches!(load(property), Err(SpzError::UnsupportedPlyProperty { .. })),
+                "{property}"
+            );
+        }
+
+        let ply = "ply\nformat ascii 1.0\nelement face 0\nproperty float x\nend_header\n";
+        let mut stream = std::io::BufReader::new(ply.as_bytes());
+        assert!(matches!(
+            load_ply_stream(&mut stream),
+            Err(SpzError::UnknownPlyElement(name)) if name == "face"
+        ));
+        let mut stream = std::io::BufReader::new("ply\nformat ascii 1.0\n".as_bytes());
+        assert!(matches!(
+            load_ply_stream(&mut stream),
+            Err(SpzError::PlyParse(_))
+        ));
+
+        // Rows that are cut short, or don't parse as their type.
+        let truncated = b"ply\nformat binary_little_endian 1.0\nelement vertex 2\nproperty float x\nend_header\n\0\0\x80\x3f";
+        for ply in [
+            &truncated[..],
+            b"ply\nformat ascii 1.0\nelement vertex 2\nproperty float x\nend_header\n1.0\n",
+            b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\nend_header\n1.0\n",
+            b"ply\nformat ascii 1.0\nelement vertex 1\nproperty uchar opacity\nend_header\n256\n",
+        ] {
+            assert!(
+                matches!(
+                    load_ply_stream(&mut std::io::BufReader::new(ply)),
+                    Err(SpzError::PlyParse(_))
+                ),
+                "{}",
+                String::from_utf8_lossy(ply)
+            );
+        }
+    }
 }
 
 #[derive(Clone, ValueEnum, Default, Debug)]
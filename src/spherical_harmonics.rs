@@ -2,7 +2,9 @@ use serde::{Deserialize, Serialize};
 use std::vec;
 use vek::Vec3;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+use crate::error::{Result, SpzError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum SphericalHarmonicsOrder {
     Order0, // 0 floats / 0 vectors
     Order1, // 9 floats / 3 vectors
@@ -82,7 +84,8 @@ impl SphericalHarmonics {
         let mut values = self.values();
         values.resize(order.vector_count(), Vec3::zero());
 
-        self.set_values(values);
+        self.set_values(values)
+            .expect("an order's vector count is always valid");
     }
 
     pub fn values(&self) -> Vec<Vec3<f32>> {
@@ -94,7 +97,9 @@ impl SphericalHarmonics {
         }
     }
 
-    pub fn set_values(&mut self, values: Vec<Vec3<f32>>) {
+    /// Replaces the coefficients, padding with zeros up to the smallest order that holds them all.
+    /// Fails if there are more than 15 vectors.
+    pub fn set_values(&mut self, values: Vec<Vec3<f32>>) -> Result<()> {
         let mut values = values;
         match values.len() {
             0 => *self = SphericalHarmonics::Order0(()),
@@ -123,8 +128,9 @@ impl SphericalHarmonics {
                     values[13], values[14],
                 ]);
             }
-            _ => panic!("Invalid number of values for SphericalHarmonics"),
+            count => return Err(SpzError::TooManyShCoefficients(count)),
         };
+        Ok(())
     }
 
     pub fn scalars(&self) -> Vec<f32> {
@@ -135,22 +141,30 @@ impl SphericalHarmonics {
             .collect()
     }
 
-    pub fn set_scalars(&mut self, scalars: &[f32]) {
+    /// Like `set_values`, with RGB interleaved scalars. A trailing partial vector is zero padded.
+    pub fn set_scalars(&mut self, scalars: &[f32]) -> Result<()> {
         let values = scalars
             .chunks(3)
-            .map(|chunk| Vec3::new(chunk[0], chunk[1], chunk[2]))
+            .map(|chunk| {
+                let mut value = Vec3::zero();
+                value[..chunk.len()].copy_from_slice(chunk);
+                value
+            })
             .collect();
-        self.set_values(values);
+        self.set_values(values)
     }
 
-    pub fn extend_scalar(&mut self, scalar_index: usize, value: f32) {
+    pub fn extend_scalar(&mut self, scalar_index: usize, value: f32) -> Result<()> {
         let sh_index = scalar_index / 3;
+        if sh_index >= SphericalHarmonicsOrder::Order3.vector_count() {
+            return Err(SpzError::TooManyShCoefficients(sh_index + 1));
+        }
         let mut values = self.values();
         if values.len() <= sh_index {
             values.resize(sh_index + 1, Vec3::zero());
         }
         values[sh_index][scalar_index % 3] = value;
-        self.set_values(values);
+        self.set_values(values)
     }
 }
 
@@ -225,8 +239,16 @@ mod test {
     fn test_extend() {
         let mut sh = SphericalHarmonics::default();
         for n in 0..45 {
-            sh.extend_scalar(n, n as f32);
+            sh.extend_scalar(n, n as f32).unwrap();
         }
+        assert!(matches!(
+            sh.extend_scalar(45, 0.0),
+            Err(SpzError::TooManyShCoefficients(16))
+        ));
+        assert!(matches!(
+            sh.set_values(vec![Vec3::zero(); 16]),
+            Err(SpzError::TooManyShCoefficients(16))
+        ));
         assert!(sh.order() == SphericalHarmonicsOrder::Order3);
         assert!(sh.order().vector_count() == 15);
         assert!(
@@ -1,19 +1,48 @@
-use anyhow::Result;
 use bytemuck::{Pod, Zeroable};
-use flate2::write::GzEncoder;
 use flate2::Compression;
+use std::f32::consts::FRAC_1_SQRT_2;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::vec;
 use vek::{Quaternion, Vec3};
 
+use crate::error::{Column, Result, SpzError};
+use crate::gaussian_cloud::GaussianCloud;
+use crate::gzip::{self, BlockIndex, ParallelGzEncoder};
+use crate::packed_gaussians::PackedGaussians;
 use crate::spherical_harmonics::{SphericalHarmonics, SphericalHarmonicsOrder};
 use crate::spz_writer::*;
-use crate::unpacked_gaussian::UnpackedGaussian;
+use crate::support::to_u8;
 
 pub(crate) const COLOR_SCALE: f32 = 0.15;
 
+bitflags::bitflags! {
+    /// A selection of the per-gaussian columns of an SPZ file, for `SPZReader::read_columns`.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct Columns: u8 {
+        const POSITIONS = 1 << 0;
+        const ALPHAS = 1 << 1;
+        const COLORS = 1 << 2;
+        const SCALES = 1 << 3;
+        const ROTATIONS = 1 << 4;
+        const SPHERICAL_HARMONICS = 1 << 5;
+    }
+}
+
+/// "NGSP" in little-endian byte order.
+pub const SPZ_MAGIC: u32 = 0x5053474e;
+
+bitflags::bitflags! {
+    /// The flags stored in `SPZHeader::flags`.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct SPZFlags: u8 {
+        /// The cloud was trained with antialiasing (Mip-Splatting style) and should be rendered
+        /// with the matching filter.
+        const ANTIALIASED = 0x1;
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 pub struct SPZHeader {
@@ -28,115 +57,279 @@ pub struct SPZHeader {
 
 impl SPZHeader {
     pub fn is_valid(&self) -> bool {
-        self.magic == 0x5053474e && self.version == 2 && self.sh_degree <= 3
+        self.magic == SPZ_MAGIC && (1..=3).contains(&self.version) && self.sh_degree <= 3
+    }
+
+    /// Like `is_valid`, but reports which field is wrong.
+    pub fn validate(&self) -> Result<()> {
+        if self.magic != SPZ_MAGIC {
+            return Err(SpzError::InvalidMagic(self.magic));
+        }
+        if !(1..=3).contains(&self.version) {
+            return Err(SpzError::UnsupportedVersion(self.version));
+        }
+        if self.sh_degree > 3 {
+            return Err(SpzError::InvalidShDegree(self.sh_degree));
+        }
+        Ok(())
     }
 
-    pub fn new(num_points: u32, sh_degree: u8, fractional_bits: u8, flags: u8) -> Self {
+    pub fn new(
+        version: u32,
+        num_points: u32,
+        sh_degree: u8,
+        fractional_bits: u8,
+        flags: SPZFlags,
+    ) -> Self {
         Self {
-            magic: 0x5053474e,
-            version: 2,
+            magic: SPZ_MAGIC,
+            version,
             num_points,
             sh_degree,
             fractional_bits,
-            flags,
+            flags: flags.bits(),
             reserved: 0,
         }
     }
 
+    /// The typed header flags. Unknown bits are retained.
+    pub fn flags(&self) -> SPZFlags {
+        SPZFlags::from_bits_retain(self.flags)
+    }
+
+    pub fn set_flags(&mut self, flags: SPZFlags) {
+        self.flags = flags.bits();
+    }
+
+    pub fn is_antialiased(&self) -> bool {
+        self.flags().contains(SPZFlags::ANTIALIASED)
+    }
+
+    /// Version 1 files store positions as half floats, later versions as 24 bit fixed point.
+    pub fn position_component_size(&self) -> usize {
+        if self.version == 1 {
+            2
+        } else {
+            3
+        }
+    }
+
+    /// Version 3 files store rotations as "smallest three" components in 4 bytes, earlier versions
+    /// store xyz in 3 bytes.
+    pub fn rotation_size(&self) -> usize {
+        if self.version >= 3 {
+            4
+        } else {
+            3
+        }
+    }
+
+    /// The number of bytes each gaussian takes up in `column`.
+    pub fn bytes_per_point(&self, column: Column) -> usize {
+        match column {
+            Column::Header => 0,
+            Column::Positions => 3 * self.position_component_size(),
+            Column::Alphas => 1,
+            Column::Colors => 3,
+            Column::Scales => 3,
+            Column::Rotations => self.rotation_size(),
+            Column::SphericalHarmonics => SphericalHarmonicsOrder::order_for_degree(self.sh_degree)
+                .map_or(0, |order| order.scalar_count()),
+        }
+    }
+
     pub fn expected_uncompressed_size(&self) -> usize {
         let header_size = std::mem::size_of::<SPZHeader>();
-        let position_size = 3 * 3;
-        let alpha_size = 1;
-        let color_size = 3;
-        let scale_size = 3;
-        let rotation_size = 3;
-        let order = SphericalHarmonicsOrder::order_for_degree(self.sh_degree).unwrap();
-        let sh_size = order.scalar_count();
-        let size_per_point =
-            position_size + alpha_size + color_size + scale_size + rotation_size + sh_size;
+        let size_per_point: usize = [
+            Column::Positions,
+            Column::Alphas,
+            Column::Colors,
+            Column::Scales,
+            Column::Rotations,
+            Column::SphericalHarmonics,
+        ]
+        .into_iter()
+        .map(|column| self.bytes_per_point(column))
+        .sum();
         header_size + size_per_point * self.num_points as usize
     }
 }
 
 pub fn write_spz_to_stream<W: Write>(
-    gaussians: &Vec<UnpackedGaussian>,
+    cloud: &GaussianCloud,
     stream: &mut W,
-    omit_spherical_harmonics: bool,
-) -> Result<()> {
-    let options = SPZWriterOptions {
-        omit_spherical_harmonics,
-    };
+    options: SPZWriterOptions,
+) -> Result<WriteReport> {
     let mut writer = SPZWriter::new(stream, options);
-    writer.write(gaussians)?;
-    Ok(())
+    writer.write(cloud)
+}
+
+/// The uncompressed size of the blocks columns are split into by `write_spz_indexed_to_stream`.
+const INDEXED_BLOCK_SIZE: usize = 1 << 20;
+
+/// Writes gzip compressed SPZ with a `BlockIndex`, for `SPZBlockReader`. The header and each
+/// column start a new gzip member, and columns are split into blocks of about a megabyte, which
+/// are compressed on `options.compression_threads` threads.
+pub fn write_spz_indexed_to_stream<W: Write>(
+    cloud: &GaussianCloud,
+    stream: &mut W,
+    options: SPZWriterOptions,
+) -> Result<WriteReport> {
+    let level = compression_level(&options)?;
+    let threads = options.compression_threads;
+    let packed = PackedGaussians::pack(cloud, &options)?;
+    let header = packed.header();
+
+    // Grow the blocks if there would be too many for the index.
+    let columns = packed.columns();
+    let total: usize = columns.iter().map(|(_, data)| data.len()).sum();
+    let block_size =
+        INDEXED_BLOCK_SIZE.max(total.div_ceil(BlockIndex::MAX_BLOCKS - columns.len() - 1));
+    let blocks = std::iter::once(bytemuck::bytes_of(&header))
+        .chain(columns.iter().flat_map(|(_, data)| data.chunks(block_size)))
+        .collect::<Vec<_>>();
+    gzip::write_indexed(stream, &blocks, level, threads)?;
+    WriteReport::new(cloud, &packed)
+}
+
+fn compression_level(options: &SPZWriterOptions) -> Result<Compression> {
+    if options.compression_level > 9 {
+        return Err(SpzError::InvalidCompressionLevel(options.compression_level));
+    }
+    Ok(Compression::new(options.compression_level))
 }
 
 pub fn write_spz(
-    gaussians: Vec<UnpackedGaussian>,
+    cloud: &GaussianCloud,
     path: &Path,
     compressed: bool,
-    omit_spherical_harmonics: bool,
-) -> Result<()> {
-    let file = File::create(path)?;
-    if compressed {
-        let mut stream = GzEncoder::new(file, Compression::best());
-        write_spz_to_stream(&gaussians, &mut stream, omit_spherical_harmonics)?;
-        stream.finish()?;
+    options: SPZWriterOptions,
+) -> Result<WriteReport> {
+    let file = BufWriter::new(File::create(path)?);
+    let (mut file, report) = write_spz_with_compression(cloud, file, compressed, options)?;
+    file.flush()?;
+    Ok(report)
+}
+
+/// Writes SPZ the way `write_spz` does, gzip compressed unless `compressed` is false, and returns
+/// the stream.
+pub(crate) fn write_spz_with_compression<W: Write>(
+    cloud: &GaussianCloud,
+    mut stream: W,
+    compressed: bool,
+    options: SPZWriterOptions,
+) -> Result<(W, WriteReport)> {
+    if compressed && options.block_index {
+        let report = write_spz_indexed_to_stream(cloud, &mut stream, options)?;
+        Ok((stream, report))
+    } else if compressed {
+        let level = compression_level(&options)?;
+        if options.compression_threads == 1 {
+            let mut stream = gzip::encoder(stream, level);
+            let report = write_spz_to_stream(cloud, &mut stream, options)?;
+            Ok((stream.finish()?, report))
+        } else {
+            let mut stream = ParallelGzEncoder::new(stream, level, options.compression_threads);
+            let report = write_spz_to_stream(cloud, &mut stream, options)?;
+            Ok((stream.finish()?, report))
+        }
     } else {
-        let mut stream = Box::new(file);
-        write_spz_to_stream(&gaussians, &mut stream, omit_spherical_harmonics)?;
+        let report = write_spz_to_stream(cloud, &mut stream, options)?;
+        Ok((stream, report))
+    }
+}
+
+/// The default bits kept per spherical harmonics coefficient, as used by the reference encoder.
+pub(crate) const SH1_BITS: u8 = 5;
+pub(crate) const SH_REST_BITS: u8 = 4;
+
+pub(crate) fn check_sh_bits(bits: u8) -> Result<()> {
+    if !(1..=8).contains(&bits) {
+        return Err(SpzError::InvalidShBits(bits));
     }
     Ok(())
 }
 
-const SH1_BITS: i32 = 5;
-const SH_REST_BITS: i32 = 4;
+/// The most fractional bits a 24 bit fixed point position can have while keeping a sign bit.
+pub(crate) const MAX_FRACTIONAL_BITS: u8 = 23;
 
-impl SphericalHarmonics {
-    pub(crate) fn from_spz_bytes(bytes: Vec<u8>) -> Self {
-        fn unquantize_sh(x: u8) -> f32 {
-            (x as f32 - 128.0) / 128.0
-        }
-        let values = bytes
-            .iter()
-            .map(|&x| unquantize_sh(x))
-            .collect::<Vec<f32>>();
-        let mut sh = SphericalHarmonics::default();
-        sh.set_scalars(&values);
-        sh
+pub(crate) fn check_fractional_bits(bits: Option<u8>) -> Result<()> {
+    match bits {
+        Some(bits) if bits > MAX_FRACTIONAL_BITS => Err(SpzError::InvalidFractionalBits(bits)),
+        _ => Ok(()),
     }
+}
 
-    pub(crate) fn spz_bytes(&self) -> Vec<u8> {
-        fn quantize_sh(x: f32, bucket_size: i32) -> u8 {
-            let mut q = ((x * 128.0).round() as i32) + 128;
-            q = (q + bucket_size / 2) / bucket_size * bucket_size;
-            q.clamp(0, 255) as u8
-        }
+/// Unquantizes SPZ spherical harmonics bytes.
+pub(crate) fn sh_scalars_from_spz_bytes(bytes: &[u8]) -> Vec<f32> {
+    fn unquantize_sh(x: u8) -> f32 {
+        (x as f32 - 128.0) / 128.0
+    }
+    bytes.iter().map(|&x| unquantize_sh(x)).collect()
+}
 
-        let scalar_count = self.order().scalar_count();
-        let scalars = self.scalars();
-        assert!(scalars.len() == scalar_count);
-        let mut sh: Vec<u8> = vec![0; scalar_count];
+/// Quantizes spherical harmonics scalars, `scalar_count` per gaussian, to SPZ bytes. Band 1 keeps
+/// `sh1_bits` bits per coefficient, higher bands keep `sh_rest_bits`. Both must be in 1..=8.
+pub(crate) fn sh_scalars_to_spz_bytes(
+    scalars: &[f32],
+    scalar_count: usize,
+    sh1_bits: u8,
+    sh_rest_bits: u8,
+) -> Vec<u8> {
+    fn quantize_sh(x: f32, bucket_size: i32) -> u8 {
+        let mut q = ((x * 128.0).round() as i32) + 128;
+        q = (q + bucket_size / 2) / bucket_size * bucket_size;
+        q.clamp(0, 255) as u8
+    }
+
+    if scalar_count == 0 {
+        return Vec::new();
+    }
+    assert!(scalars.len().is_multiple_of(scalar_count));
+    let mut sh: Vec<u8> = vec![0; scalars.len()];
 
-        let i = 0;
+    for i in (0..scalars.len()).step_by(scalar_count) {
         let mut j = 0;
-        while j < 9 {
-            sh[i + j] = quantize_sh(scalars[i + j], 1 << (8 - SH1_BITS));
+        while j < 9.min(scalar_count) {
+            sh[i + j] = quantize_sh(scalars[i + j], 1 << (8 - sh1_bits));
             j += 1;
         }
         while j < scalar_count {
-            sh[i + j] = quantize_sh(scalars[i + j], 1 << (8 - SH_REST_BITS));
+            sh[i + j] = quantize_sh(scalars[i + j], 1 << (8 - sh_rest_bits));
             j += 1;
         }
-        sh
+    }
+    sh
+}
+
+impl SphericalHarmonics {
+    pub fn from_spz_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let mut sh = SphericalHarmonics::default();
+        sh.set_scalars(&sh_scalars_from_spz_bytes(&bytes))?;
+        Ok(sh)
+    }
+
+    /// Quantizes the coefficients, keeping `sh1_bits` bits for band 1 and `sh_rest_bits` for the
+    /// higher bands. See `SPZWriterOptions`.
+    pub fn spz_bytes(&self, sh1_bits: u8, sh_rest_bits: u8) -> Result<Vec<u8>> {
+        check_sh_bits(sh1_bits)?;
+        check_sh_bits(sh_rest_bits)?;
+        Ok(sh_scalars_to_spz_bytes(
+            &self.scalars(),
+            self.order().scalar_count(),
+            sh1_bits,
+            sh_rest_bits,
+        ))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use approx::assert_relative_eq;
+    use flate2::write::GzEncoder;
 
     use super::*;
+    use crate::gaussian_cloud::ShDegreePolicy;
     use crate::spz_reader::*;
     use crate::unpacked_gaussian::*;
     use vek::{Quaternion, Vec3};
@@ -153,12 +346,22 @@ mod tests {
         };
 
         let mut buffer = Vec::new();
-        write_spz_to_stream(&vec![gaussian], &mut buffer, true).unwrap();
+        let options = SPZWriterOptions {
+            max_sh_degree: 0,
+            ..Default::default()
+        };
+        write_spz_to_stream(
+            &GaussianCloud::from_gaussians(&[gaussian]).unwrap(),
+            &mut buffer,
+            options,
+        )
+        .unwrap();
 
         let result =
             SPZReader::new_from_slice(&buffer, SPZReaderOptions::default().skip_compression(true))
                 .read()
-                .unwrap();
+                .unwrap()
+                .to_gaussians();
         assert!(result.len() == 1);
         let result = &result[0];
         gaussian_approx_eq(&gaussian, result);
@@ -174,7 +377,8 @@ mod tests {
         let gaussians =
             SPZReader::new_from_slice(&bytes, SPZReaderOptions::default().skip_compression(true))
                 .read()
-                .unwrap();
+                .unwrap()
+                .to_gaussians();
         assert!(gaussians.len() == 1);
         let gaussian = &gaussians[0];
         assert!(gaussian.position == Vec3::new(100.0, 200.0, -100.0));
@@ -189,6 +393,434 @@ mod tests {
         assert_relative_eq!(gaussian.color[2], 0.25, epsilon = 1e-2);
     }
 
+    #[test]
+    fn test_detect_compression() {
+        let cloud = GaussianCloud::from_gaussians(&[UnpackedGaussian::default()]).unwrap();
+        let mut raw = Vec::new();
+        write_spz_to_stream(&cloud, &mut raw, SPZWriterOptions::default()).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut encoder = crate::gzip::ParallelGzEncoder::with_block_size(
+            Vec::new(),
+            Compression::default(),
+            2,
+            8,
+        );
+        encoder.write_all(&raw).unwrap();
+        let multi_member = encoder.finish().unwrap();
+
+        for bytes in [&raw, &compressed, &multi_member] {
+            let result = SPZReader::new_from_slice(bytes, SPZReaderOptions::default())
+                .read()
+                .unwrap();
+            assert_eq!(result.len(), 1);
+        }
+
+        // An explicit override still wins over detection.
+        assert!(SPZReader::new_from_slice(
+            &raw,
+            SPZReaderOptions::default().skip_compression(false)
+        )
+        .read()
+        .is_err());
+
+        // Detection keeps reading when a stream returns one byte at a time.
+        struct OneByte<'a>(&'a [u8]);
+        impl std::io::Read for OneByte<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let count = self.0.len().min(buf.len()).min(1);
+                buf[..count].copy_from_slice(&self.0[..count]);
+                self.0 = &self.0[count..];
+                Ok(count)
+            }
+        }
+        for bytes in [&raw, &compressed] {
+            let mut reader = SPZReader::new(Box::new(OneByte(bytes)), SPZReaderOptions::default());
+            assert_eq!(reader.read().unwrap().len(), 1);
+        }
+        for bytes in [&b"PK\x03\x04"[..], &[0x1f][..], &[]] {
+            assert!(matches!(
+                SPZReader::new_from_slice(bytes, SPZReaderOptions::default()).read(),
+                Err(SpzError::UnrecognizedFormat(prefix)) if prefix == bytes
+            ));
+        }
+    }
+
+    #[test]
+    fn test_sh_bits() {
+        let sh = SphericalHarmonics::Order2([Vec3::broadcast(0.1); 8]);
+        assert_eq!(sh.spz_bytes(8, 8).unwrap(), vec![141; 24]);
+        assert_eq!(sh.spz_bytes(SH1_BITS, SH_REST_BITS).unwrap(), vec![144; 24]);
+        let bytes = sh.spz_bytes(4, 3).unwrap();
+        assert_eq!(bytes[..9], [144; 9]);
+        assert_eq!(bytes[9..], [128; 15]);
+        assert!(matches!(
+            sh.spz_bytes(0, 4),
+            Err(SpzError::InvalidShBits(0))
+        ));
+        assert!(matches!(
+            sh.spz_bytes(5, 9),
+            Err(SpzError::InvalidShBits(9))
+        ));
+
+        let cloud = GaussianCloud::from_gaussians(&[UnpackedGaussian {
+            spherical_harmonics: sh,
+            ..Default::default()
+        }])
+        .unwrap();
+        let options = SPZWriterOptions {
+            sh1_bits: 8,
+            sh_rest_bits: 8,
+            ..Default::default()
+        };
+        let mut buffer = Vec::new();
+        write_spz_to_stream(&cloud, &mut buffer, options).unwrap();
+        assert!(buffer.ends_with(&[141; 24]));
+    }
+
+    #[test]
+    fn test_max_sh_degree() {
+        let cloud = GaussianCloud::from_gaussians(&[UnpackedGaussian {
+            spherical_harmonics: SphericalHarmonics::Order3([Vec3::broadcast(0.5); 15]),
+            ..Default::default()
+        }])
+        .unwrap();
+        for max_sh_degree in 0..=3 {
+            let options = SPZWriterOptions {
+                max_sh_degree,
+                ..Default::default()
+            };
+            let mut buffer = Vec::new();
+            write_spz_to_stream(&cloud, &mut buffer, options).unwrap();
+            let result = SPZReader::new_from_slice(&buffer, SPZReaderOptions::default())
+                .read()
+                .unwrap();
+            assert_eq!(result.sh_degree, max_sh_degree);
+            assert_eq!(
+                result.spherical_harmonics,
+                cloud.sh_scalars(0).unwrap()[..result.sh_scalar_count()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_mixed_sh_degrees() {
+        let gaussians = [
+            UnpackedGaussian {
+                spherical_harmonics: SphericalHarmonics::Order2([Vec3::broadcast(0.5); 8]),
+                ..Default::default()
+            },
+            UnpackedGaussian::default(),
+        ];
+        for (policy, sh_degree) in [(ShDegreePolicy::Promote, 2), (ShDegreePolicy::Truncate, 0)] {
+            let cloud = GaussianCloud::from_gaussians_with_policy(&gaussians, policy).unwrap();
+            let mut buffer = Vec::new();
+            write_spz_to_stream(&cloud, &mut buffer, SPZWriterOptions::default()).unwrap();
+            let result = SPZReader::new_from_slice(&buffer, SPZReaderOptions::default())
+                .read()
+                .unwrap();
+            assert_eq!(result.sh_degree, sh_degree);
+            assert_eq!(result.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_version_1() {
+        // Same gaussian as `test_single`, but with float16 positions as written by the v1 encoder.
+        let hex = "4E475350 01000000 01000000 00000000 4056 405A 40D6 B8 A69389 B090B0 8080FF";
+        let bytes = dehex(hex);
+
+        let mut reader =
+            SPZReader::new_from_slice(&bytes, SPZReaderOptions::default().skip_compression(true));
+        let header = reader.read_header().unwrap();
+        assert_eq!(header.version, 1);
+        assert_eq!(header.expected_uncompressed_size(), bytes.len());
+        let gaussians = reader.read_gaussians().unwrap().to_gaussians();
+        assert!(gaussians.len() == 1);
+        let gaussian = &gaussians[0];
+        assert!(gaussian.position == Vec3::new(100.0, 200.0, -100.0));
+        assert!(gaussian.scales == Vec3::new(1.0, -1.0, 1.0));
+        assert_relative_eq!(gaussian.rotation.z, 1.0, epsilon = 1e-2);
+        assert_relative_eq!(gaussian.alpha, 0.95, epsilon = 1e-2);
+        assert_relative_eq!(gaussian.color[0], 1.0, epsilon = 1e-2);
+    }
+
+    #[test]
+    fn test_antialiased_flag() {
+        let mut cloud = GaussianCloud::from_gaussians(&[UnpackedGaussian::default()]).unwrap();
+        for antialiased in [false, true] {
+            cloud.antialiased = antialiased;
+            let mut buffer = Vec::new();
+            write_spz_to_stream(&cloud, &mut buffer, SPZWriterOptions::default()).unwrap();
+            assert_eq!(buffer[14], antialiased as u8);
+
+            let mut reader = SPZReader::new_from_slice(
+                &buffer,
+                SPZReaderOptions::default().skip_compression(true),
+            );
+            let header = reader.read_header().unwrap();
+            assert_eq!(header.is_antialiased(), antialiased);
+            assert_eq!(
+                header.flags().contains(SPZFlags::ANTIALIASED),
+                reader.is_antialiased()
+            );
+            assert_eq!(reader.read_gaussians().unwrap().antialiased, antialiased);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let read = |bytes: &[u8]| {
+            SPZReader::new_from_slice(bytes, SPZReaderOptions::default().skip_compression(true))
+                .read()
+        };
+
+        let mut bytes = dehex("4E475350 02000000 01000000 000C0000");
+        bytes[0] = 0;
+        assert!(matches!(
+            read(&bytes),
+            Err(SpzError::InvalidMagic(0x50534700))
+        ));
+
+        let bytes = dehex("4E475350 09000000 01000000 000C0000");
+        assert!(matches!(read(&bytes), Err(SpzError::UnsupportedVersion(9))));
+
+        let bytes = dehex("4E475350 02000000 01000000 040C0000");
+        assert!(matches!(read(&bytes), Err(SpzError::InvalidShDegree(4))));
+
+        let bytes = dehex("4E475350 02000000");
+        assert!(matches!(
+            read(&bytes),
+            Err(SpzError::Truncated {
+                column: Column::Header,
+                offset: 8,
+                expected: 16,
+                actual: 8
+            })
+        ));
+
+        // Cut the single gaussian from `test_single` off in the middle of its colors.
+        let bytes = dehex("4E475350 02000000 01000000 000C0000 00400600 800C00C0 F9B8A6");
+        assert!(matches!(
+            read(&bytes),
+            Err(SpzError::Truncated {
+                column: Column::Colors,
+                offset: 27,
+                expected: 3,
+                actual: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let single =
+            "4E475350 02000000 01000000 000C0000 00400600 800C00C0 F9B8A693 89B090B0 8080FF";
+        let options = || SPZReaderOptions::default().skip_compression(true);
+
+        let bytes = dehex(&format!("{single} 00"));
+        assert!(matches!(
+            SPZReader::new_from_slice(&bytes, options()).read(),
+            Err(SpzError::TrailingData { expected: 35 })
+        ));
+        assert!(matches!(
+            SPZReader::new_from_slice(&bytes, options()).read_packed(),
+            Err(SpzError::TrailingData { expected: 35 })
+        ));
+
+        let bytes = dehex(single);
+        assert!(matches!(
+            SPZReader::new_from_slice(&bytes, options().max_points(0)).read(),
+            Err(SpzError::TooManyPoints {
+                num_points: 1,
+                max: 0
+            })
+        ));
+        assert!(matches!(
+            SPZReader::new_from_slice(&bytes, options().max_uncompressed_size(34)).read(),
+            Err(SpzError::TooLarge { size: 35, max: 34 })
+        ));
+        assert!(
+            SPZReader::new_from_slice(&bytes, options().max_uncompressed_size(35))
+                .read()
+                .is_ok()
+        );
+
+        // A header claiming billions of gaussians is rejected by the default limits, and even
+        // without them must not allocate for all of them.
+        let bytes = dehex("4E475350 02000000 FFFFFFFF 030C0000 00400600");
+        assert!(matches!(
+            SPZReader::new_from_slice(&bytes, options()).read(),
+            Err(SpzError::TooManyPoints {
+                max: DEFAULT_MAX_POINTS,
+                ..
+            })
+        ));
+        assert!(matches!(
+            SPZReader::new_from_slice(&bytes, options().unlimited()).read(),
+            Err(SpzError::Truncated {
+                column: Column::Positions,
+                offset: 20,
+                actual: 4,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_read_columns() {
+        let bytes =
+            dehex("4E475350 02000000 01000000 000C0000 00400600 800C00C0 F9B8A693 89B090B0 8080FF");
+        let options = || SPZReaderOptions::default().skip_compression(true);
+
+        let columns = SPZReader::new_from_slice(&bytes, options())
+            .read_columns(Columns::POSITIONS | Columns::ALPHAS)
+            .unwrap();
+        assert_eq!(columns.num_points, 1);
+        assert_eq!(columns.positions, vec![Vec3::new(100.0, 200.0, -100.0)]);
+        assert_relative_eq!(columns.alphas[0], 0.95, epsilon = 1e-2);
+        assert!(columns.colors.is_empty());
+        assert!(columns.scales.is_empty());
+        assert!(columns.rotations.is_empty());
+        assert!(columns.spherical_harmonics.is_empty());
+
+        // Skipped columns are not decoded, but still have to be present.
+        let columns = SPZReader::new_from_slice(&bytes, options())
+            .read_columns(Columns::SCALES)
+            .unwrap();
+        assert!(columns.positions.is_empty());
+        assert_eq!(columns.scales, vec![Vec3::new(1.0, -1.0, 1.0)]);
+        let result =
+            SPZReader::new_from_slice(&bytes[..28], options()).read_columns(Columns::SCALES);
+        assert!(matches!(
+            result,
+            Err(SpzError::Truncated {
+                column: Column::Colors,
+                offset: 28,
+                expected: 3,
+                actual: 2
+            })
+        ));
+
+        // Nothing after the last requested column is read.
+        let columns = SPZReader::new_from_slice(&bytes[..25], options())
+            .read_columns(Columns::POSITIONS)
+            .unwrap();
+        assert_eq!(columns.positions, vec![Vec3::new(100.0, 200.0, -100.0)]);
+    }
+
+    #[test]
+    fn test_block_index() {
+        // Enough degree 3 gaussians for the spherical harmonics to span two blocks.
+        let gaussians = (0..30_000)
+            .map(|n| UnpackedGaussian {
+                position: Vec3::new(n as f32 / 1000.0, 1.0, -2.0),
+                spherical_harmonics: SphericalHarmonics::Order3(
+                    [Vec3::broadcast((n % 100) as f32 / 200.0); 15],
+                ),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+        let mut bytes = Vec::new();
+        let options = || SPZWriterOptions {
+            compression_threads: 2,
+            ..Default::default()
+        };
+        write_spz_indexed_to_stream(&cloud, &mut bytes, options()).unwrap();
+        let index = crate::gzip::BlockIndex::read_from(&mut bytes.as_slice())
+            .unwrap()
+            .unwrap();
+        assert_eq!(index.blocks.len(), 8);
+
+        // Readers without index support see ordinary multi-member gzip.
+        let expected = SPZReader::new_from_slice(&bytes, SPZReaderOptions::default())
+            .read()
+            .unwrap();
+        assert_eq!(expected.len(), 30_000);
+
+        let mut reader =
+            SPZBlockReader::new(std::io::Cursor::new(&bytes), SPZReaderOptions::default()).unwrap();
+        assert_eq!(reader.header.num_points, 30_000);
+        let result = reader.read().unwrap();
+        assert_eq!(result.to_gaussians(), expected.to_gaussians());
+        let columns = reader
+            .read_columns(Columns::SPHERICAL_HARMONICS | Columns::ALPHAS)
+            .unwrap();
+        assert_eq!(columns.spherical_harmonics, expected.spherical_harmonics);
+        assert_eq!(columns.alphas, expected.alphas);
+        assert!(columns.positions.is_empty());
+
+        assert!(matches!(
+            SPZBlockReader::new(
+                std::io::Cursor::new(&bytes),
+                SPZReaderOptions::default().max_points(10)
+            ),
+            Err(SpzError::TooManyPoints { .. })
+        ));
+
+        let mut plain = Vec::new();
+        write_spz_to_stream(&cloud, &mut plain, options()).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let plain = encoder.finish().unwrap();
+        assert!(matches!(
+            SPZBlockReader::new(std::io::Cursor::new(&plain), SPZReaderOptions::default()),
+            Err(SpzError::MissingBlockIndex)
+        ));
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let gaussians = (0..1000)
+            .map(|n| UnpackedGaussian {
+                position: Vec3::new((n * 37 % 101) as f32, (n % 7) as f32, n as f32 / 10.0),
+                alpha: 0.5,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let encode = |cloud: &GaussianCloud, name: &str, options: SPZWriterOptions| {
+            let path = dir.path().join(name);
+            write_spz(cloud, &path, true, options).unwrap();
+            std::fs::read(path).unwrap()
+        };
+
+        // Sorting is repeated for each encoding, so it has to be deterministic too.
+        let prepare = |sort: bool| {
+            if !sort {
+                return cloud.clone();
+            }
+            let indices = crate::hilbert_curve::hilbert_sort(&(0..cloud.len()).collect(), |&i| {
+                cloud.positions[i]
+            });
+            cloud.permute(&indices).unwrap()
+        };
+        for sort in [false, true] {
+            for options in [
+                SPZWriterOptions::default,
+                || SPZWriterOptions {
+                    compression_threads: 4,
+                    ..Default::default()
+                },
+                || SPZWriterOptions {
+                    block_index: true,
+                    ..Default::default()
+                },
+            ] {
+                let first = encode(&prepare(sort), "first.spz", options());
+                let second = encode(&prepare(sort), "second.spz", options());
+                // No file name and no modification time.
+                const FNAME: u8 = 0x08;
+                assert_eq!(first[3] & FNAME, 0);
+                assert_eq!(first[4..8], [0; 4]);
+                assert_eq!(first, second);
+            }
+        }
+    }
+
     fn dehex(hex: &str) -> Vec<u8> {
         hex.replace(" ", "")
             .as_bytes()
@@ -302,18 +934,64 @@ mod tests {
         };
 
         let mut buffer = Vec::new();
-        let gaussians = vec![gaussian];
-        write_spz_to_stream(&gaussians, &mut buffer, false).unwrap();
+        let cloud = GaussianCloud::from_gaussians(&[gaussian]).unwrap();
+        write_spz_to_stream(&cloud, &mut buffer, SPZWriterOptions::default()).unwrap();
         let options = SPZReaderOptions::default().skip_compression(true);
         let mut reader = SPZReader::new_from_slice(&buffer, options);
         let header = reader.read_header().unwrap();
         assert!(header.expected_uncompressed_size() == buffer.len());
-        let result = reader.read_gaussians().unwrap()[0];
+        let result = reader.read_gaussians().unwrap().gaussian(0).unwrap();
         assert!(gaussian.position == result.position);
         assert!(gaussian.scales == result.scales);
         gaussian_approx_eq(&gaussian, &result);
     }
 
+    #[test]
+    fn test_version_2_and_3_rotations() {
+        let rotations = [
+            Quaternion::identity(),
+            Quaternion::from_xyzw(0.9401622, 0.09101284, -0.03510389, 0.090294436),
+            Quaternion::from_xyzw(1.0344028, -0.19919053, -0.10477345, -0.014542822),
+            Quaternion::from_xyzw(-0.35686272, 0.62352943, 0.24705887, 0.6502476),
+            Quaternion::from_xyzw(0.1, -0.2, 0.3, -0.9),
+        ];
+        let gaussians = rotations
+            .iter()
+            .map(|&rotation| UnpackedGaussian {
+                rotation,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+
+        let read = |version: u32| {
+            let mut buffer = Vec::new();
+            let options = SPZWriterOptions {
+                version,
+                ..Default::default()
+            };
+            write_spz_to_stream(&cloud, &mut buffer, options).unwrap();
+            let mut reader = SPZReader::new_from_slice(
+                &buffer,
+                SPZReaderOptions::default().skip_compression(true),
+            );
+            let header = reader.read_header().unwrap();
+            assert_eq!(header.version, version);
+            assert_eq!(header.expected_uncompressed_size(), buffer.len());
+            reader.read_gaussians().unwrap().to_gaussians()
+        };
+        let v2 = read(2);
+        let v3 = read(3);
+
+        for ((original, v2), v3) in rotations.iter().zip(v2.iter()).zip(v3.iter()) {
+            // q and -q describe the same rotation.
+            let original = original.normalized();
+            assert_relative_eq!(original.dot(v2.rotation).abs(), 1.0, epsilon = 1e-2);
+            assert_relative_eq!(original.dot(v3.rotation).abs(), 1.0, epsilon = 1e-3);
+            assert_relative_eq!(v2.rotation.dot(v3.rotation).abs(), 1.0, epsilon = 1e-2);
+        }
+    }
+
     fn gaussian_approx_eq(left: &UnpackedGaussian, right: &UnpackedGaussian) {
         assert!(left.position == right.position);
         assert!(left.scales == right.scales);
@@ -329,10 +1007,9 @@ mod tests {
     }
 }
 
+/// Maps `v` from [min, max] to the nearest byte. Out of range values are clamped.
 pub fn encode(v: f32, min: f32, max: f32) -> u8 {
-    assert!(v >= min && v <= max);
-    let v = (v - min) / (max - min) * 255.0;
-    v as u8
+    to_u8((v - min) / (max - min) * 255.0)
 }
 
 pub fn decode(v: u8, min: f32, max: f32) -> f32 {
@@ -349,13 +1026,15 @@ mod test_encode {
     #[test]
     fn test_encode() {
         assert_eq!(encode(0.0, 0.0, 1.0), 0);
-        assert_eq!(encode(0.5, 0.0, 1.0), 127);
+        assert_eq!(encode(0.5, 0.0, 1.0), 128);
         assert_eq!(encode(1.0, 0.0, 1.0), 255);
         assert_eq!(encode(-1.0, -1.0, 1.0), 0);
-        assert_eq!(encode(-0.5, -1.0, 1.0), 63);
-        assert_eq!(encode(0.0, -1.0, 1.0), 127);
+        assert_eq!(encode(-0.5, -1.0, 1.0), 64);
+        assert_eq!(encode(0.0, -1.0, 1.0), 128);
         assert_eq!(encode(0.5, -1.0, 1.0), 191);
         assert_eq!(encode(1.0, -1.0, 1.0), 255);
+        assert_eq!(encode(1.5, -1.0, 1.0), 255);
+        assert_eq!(encode(-1.5, -1.0, 1.0), 0);
     }
 
     #[test]
@@ -371,34 +1050,88 @@ mod test_encode {
     }
 }
 
+/// A version 1/2 rotation: the xyz components of the normalized quaternion, each mapped from [-1, 1]
+/// to a byte. w is reconstructed on read as positive, so callers should negate quaternions with a
+/// negative w first.
 #[derive(Debug)]
 pub struct SPZQuaternion(pub [u8; 3]);
 
-impl Into<Quaternion<f32>> for SPZQuaternion {
-    fn into(self) -> Quaternion<f32> {
-        let x = decode(self.0[0], -1.0, 1.0);
-        let y = decode(self.0[1], -1.0, 1.0);
-        let z = decode(self.0[2], -1.0, 1.0);
+impl From<SPZQuaternion> for Quaternion<f32> {
+    fn from(q: SPZQuaternion) -> Self {
+        let x = decode(q.0[0], -1.0, 1.0);
+        let y = decode(q.0[1], -1.0, 1.0);
+        let z = decode(q.0[2], -1.0, 1.0);
         let xyz = Vec3::new(x, y, z);
         let w = f32::max(0.0, 1.0 - xyz.dot(xyz)).sqrt();
-        println!("*** {:?} {:?}", xyz, w);
         Quaternion::from_xyzw(xyz[0], xyz[1], xyz[2], w)
     }
 }
 
 impl From<Quaternion<f32>> for SPZQuaternion {
     fn from(q: Quaternion<f32>) -> Self {
-        fn convert(v: f32, w: f32) -> u8 {
-            encode(v, -1.0, 1.0)
-        }
         let q = q.normalized();
-        let x = convert(q.x, q.w);
-        let y = convert(q.y, q.w);
-        let z = convert(q.z, q.w);
+        let x = encode(q.x, -1.0, 1.0);
+        let y = encode(q.y, -1.0, 1.0);
+        let z = encode(q.z, -1.0, 1.0);
         SPZQuaternion([x, y, z])
     }
 }
 
+const SMALLEST_THREE_MASK: u32 = (1 << 9) - 1;
+
+/// A version 3 rotation: the index of the largest component of the normalized quaternion in the top
+/// 2 bits, followed by the three remaining components as 10 bits each (a sign bit and 9 bits of
+/// magnitude in [0, 1/sqrt(2)]), packed into a little-endian u32. Components are ordered x, y, z, w.
+#[derive(Debug)]
+pub struct SPZSmallestThreeQuaternion(pub [u8; 4]);
+
+impl From<SPZSmallestThreeQuaternion> for Quaternion<f32> {
+    fn from(q: SPZSmallestThreeQuaternion) -> Self {
+        let mut packed = u32::from_le_bytes(q.0);
+        let largest = (packed >> 30) as usize;
+        let mut components = [0.0f32; 4];
+        let mut sum_squares = 0.0;
+        for i in (0..4).rev() {
+            if i == largest {
+                continue;
+            }
+            let magnitude = packed & SMALLEST_THREE_MASK;
+            let negative = (packed >> 9) & 1 == 1;
+            packed >>= 10;
+            let v = FRAC_1_SQRT_2 * magnitude as f32 / SMALLEST_THREE_MASK as f32;
+            components[i] = if negative { -v } else { v };
+            sum_squares += v * v;
+        }
+        components[largest] = f32::max(0.0, 1.0 - sum_squares).sqrt();
+        Quaternion::from_xyzw(components[0], components[1], components[2], components[3])
+    }
+}
+
+impl From<Quaternion<f32>> for SPZSmallestThreeQuaternion {
+    fn from(q: Quaternion<f32>) -> Self {
+        let q = q.normalized();
+        let components = [q.x, q.y, q.z, q.w];
+        let mut largest = 0;
+        for i in 1..4 {
+            if components[i].abs() > components[largest].abs() {
+                largest = i;
+            }
+        }
+        // The largest component is always reconstructed as positive, so flip the others instead.
+        let negate = components[largest] < 0.0;
+        let mut packed = largest as u32;
+        for (i, &v) in components.iter().enumerate() {
+            if i == largest {
+                continue;
+            }
+            let negative = ((v < 0.0) ^ negate) as u32;
+            let magnitude = (SMALLEST_THREE_MASK as f32 * (v.abs() / FRAC_1_SQRT_2) + 0.5) as u32;
+            packed = (packed << 10) | (negative << 9) | magnitude.min(SMALLEST_THREE_MASK);
+        }
+        SPZSmallestThreeQuaternion(packed.to_le_bytes())
+    }
+}
+
 #[cfg(test)]
 mod test_quaternion {
 
@@ -415,7 +1148,6 @@ mod test_quaternion {
         assert_relative_eq!(q.normalized().z, q2.z, epsilon = 1e-1);
         assert_relative_eq!(q.normalized().w, q2.w, epsilon = 1e-1);
 
-
         let q = vek::Quaternion::from_xyzw(1.0344028, -0.19919053, -0.10477345, -0.014542822);
         let spz_q: SPZQuaternion = q.into();
         let q2: Quaternion<f32> = spz_q.into();
@@ -424,4 +1156,24 @@ mod test_quaternion {
         assert_relative_eq!(q.normalized().z, q2.z, epsilon = 1e-1);
         assert_relative_eq!(q.normalized().w, q2.w, epsilon = 1e-1);
     }
+
+    #[test]
+    fn test_smallest_three() {
+        let quaternions = [
+            Quaternion::identity(),
+            Quaternion::from_xyzw(0.9401622, 0.09101284, -0.03510389, 0.090294436),
+            Quaternion::from_xyzw(1.0344028, -0.19919053, -0.10477345, -0.014542822),
+            Quaternion::from_xyzw(0.1, -0.2, 0.3, -0.9),
+            Quaternion::from_xyzw(0.5, 0.5, -0.5, 0.5),
+        ];
+        for q in quaternions {
+            let spz_q: SPZSmallestThreeQuaternion = q.into();
+            let q2: Quaternion<f32> = spz_q.into();
+            assert_relative_eq!(q.normalized().dot(q2).abs(), 1.0, epsilon = 1e-4);
+        }
+
+        // The largest component (w) is stored as an index, the rest are zero magnitude.
+        let spz_q: SPZSmallestThreeQuaternion = Quaternion::<f32>::identity().into();
+        assert_eq!(spz_q.0, [0, 0, 0, 0xC0]);
+    }
 }
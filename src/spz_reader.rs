@@ -1,54 +1,192 @@
-use anyhow::{Context, Result};
-use itertools;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::mem::size_of;
+use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::path::Path;
-use std::vec;
-use vek::{Quaternion, Vec3};
 
-use crate::fixedpoint24::FixedPoint24;
-use crate::spherical_harmonics::{SphericalHarmonics, SphericalHarmonicsOrder};
-use crate::support::{inv_sigmoid, ReadExt};
-use crate::unpacked_gaussian::UnpackedGaussian;
+use crate::error::{Column, Result, SpzError};
+use crate::gaussian_cloud::GaussianCloud;
+use crate::gzip::{parallel_map, BlockIndex};
+use crate::packed_gaussians::*;
+use crate::support::ReadExt;
 
 use crate::spz_format::*;
 
-use flate2::read::GzDecoder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
 
-#[derive(Debug, Default)]
+/// The default `SPZReaderOptions::max_points`, the limit of the reference implementation.
+pub const DEFAULT_MAX_POINTS: usize = 10_000_000;
+
+/// The default `SPZReaderOptions::max_uncompressed_size`, 1 GiB. Room for `DEFAULT_MAX_POINTS`
+/// degree 3 gaussians, at 65 bytes each.
+pub const DEFAULT_MAX_UNCOMPRESSED_SIZE: usize = 1 << 30;
+
+#[derive(Debug, Clone)]
 pub struct SPZReaderOptions {
-    pub skip_compression: bool,
+    /// Whether the payload is raw (uncompressed) SPZ. `None`, the default, detects this from the
+    /// first bytes: gzip streams start with `1f 8b` and raw SPZ with the `NGSP` magic. Anything
+    /// else fails with `SpzError::UnrecognizedFormat`. Multi-member gzip streams are read in full.
+    pub skip_compression: Option<bool>,
+    /// The largest number of gaussians a header may declare. Defaults to `DEFAULT_MAX_POINTS`, so
+    /// untrusted input is limited unless the caller raises it. Unlimited if `None`.
+    pub max_points: Option<usize>,
+    /// The largest uncompressed payload, header included, a header may declare. Defaults to
+    /// `DEFAULT_MAX_UNCOMPRESSED_SIZE`. Unlimited if `None`.
+    pub max_uncompressed_size: Option<usize>,
+}
+
+impl Default for SPZReaderOptions {
+    fn default() -> Self {
+        Self {
+            skip_compression: None,
+            max_points: Some(DEFAULT_MAX_POINTS),
+            max_uncompressed_size: Some(DEFAULT_MAX_UNCOMPRESSED_SIZE),
+        }
+    }
 }
 
 impl SPZReaderOptions {
     pub fn new(skip_compression: bool) -> Self {
-        Self { skip_compression }
+        Self {
+            skip_compression: Some(skip_compression),
+            ..Default::default()
+        }
     }
 
+    /// Overrides compression detection.
     pub fn skip_compression(mut self, skip: bool) -> Self {
-        self.skip_compression = skip;
+        self.skip_compression = Some(skip);
+        self
+    }
+
+    pub fn max_points(mut self, max_points: usize) -> Self {
+        self.max_points = Some(max_points);
+        self
+    }
+
+    pub fn max_uncompressed_size(mut self, max_uncompressed_size: usize) -> Self {
+        self.max_uncompressed_size = Some(max_uncompressed_size);
         self
     }
+
+    /// Removes both limits, for trusted input.
+    pub fn unlimited(mut self) -> Self {
+        self.max_points = None;
+        self.max_uncompressed_size = None;
+        self
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads the first 4 bytes, or fewer if the stream ends first. A single `read`, or `fill_buf`, may
+/// return less from a pipe or socket.
+fn read_prefix(reader: &mut dyn Read) -> std::io::Result<Vec<u8>> {
+    let mut prefix = [0; 4];
+    let mut len = 0;
+    while len < prefix.len() {
+        match reader.read(&mut prefix[len..]) {
+            Ok(0) => break,
+            Ok(count) => len += count,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(prefix[..len].to_vec())
+}
+
+/// Columns are read in chunks of this size, so that a header claiming more data than the stream
+/// holds can't force a large allocation up front.
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+fn check_limits(
+    header: &SPZHeader,
+    max_points: Option<usize>,
+    max_uncompressed_size: Option<usize>,
+) -> Result<()> {
+    let num_points = header.num_points as usize;
+    if let Some(max) = max_points.filter(|&max| num_points > max) {
+        return Err(SpzError::TooManyPoints { num_points, max });
+    }
+    let size = header.expected_uncompressed_size();
+    if let Some(max) = max_uncompressed_size.filter(|&max| size > max) {
+        return Err(SpzError::TooLarge { size, max });
+    }
+    Ok(())
+}
+
+/// Decodes one column's data into `cloud`.
+fn decode_column(cloud: &mut GaussianCloud, column: Column, data: &[u8], header: &SPZHeader) {
+    match column {
+        Column::Positions => cloud.positions = decode_positions(data, header),
+        Column::Alphas => cloud.alphas = decode_alphas(data),
+        Column::Colors => cloud.colors = decode_colors(data),
+        Column::Scales => cloud.scales = decode_scales(data),
+        Column::Rotations => cloud.rotations = decode_rotations(data, header),
+        Column::SphericalHarmonics => cloud.spherical_harmonics = sh_scalars_from_spz_bytes(data),
+        Column::Header => unreachable!(),
+    }
+}
+
+/// The columns of an SPZ file, in the order they are stored.
+const COLUMN_LAYOUT: [(Columns, Column); 6] = [
+    (Columns::POSITIONS, Column::Positions),
+    (Columns::ALPHAS, Column::Alphas),
+    (Columns::COLORS, Column::Colors),
+    (Columns::SCALES, Column::Scales),
+    (Columns::ROTATIONS, Column::Rotations),
+    (Columns::SPHERICAL_HARMONICS, Column::SphericalHarmonics),
+];
+
+/// Detects whether `reader` is raw SPZ from its first bytes. Returns the reader with those bytes
+/// put back, and any error for the first read to report.
+fn detect_format<'a>(
+    mut reader: Box<dyn Read + 'a>,
+) -> (Box<dyn Read + 'a>, bool, Option<SpzError>) {
+    let prefix = match read_prefix(&mut reader) {
+        Ok(prefix) => prefix,
+        Err(e) => return (reader, true, Some(e.into())),
+    };
+    let skip_compression = !prefix.starts_with(&GZIP_MAGIC);
+    let error = (skip_compression && prefix != SPZ_MAGIC.to_le_bytes())
+        .then(|| SpzError::UnrecognizedFormat(prefix.clone()));
+    let reader = Box::new(std::io::Cursor::new(prefix).chain(reader));
+    (reader, skip_compression, error)
 }
 
 pub struct SPZReader<'a> {
     reader: Box<dyn Read + 'a>,
+    /// The number of uncompressed bytes consumed so far.
+    offset: usize,
+    max_points: Option<usize>,
+    max_uncompressed_size: Option<usize>,
+    /// A failure detecting the format, reported by the first read.
+    detection_error: Option<SpzError>,
     pub header: Option<SPZHeader>,
-    pub gaussians: Option<Vec<UnpackedGaussian>>,
 }
 
 impl<'a> SPZReader<'a> {
+    /// Creates a reader. Unless `SPZReaderOptions::skip_compression` is set, the first bytes are
+    /// read to detect the format, and a failure to do so is returned by the first read.
     pub fn new(reader: Box<dyn Read + 'a>, options: SPZReaderOptions) -> Self {
-        let reader = if !options.skip_compression {
-            Box::new(GzDecoder::new(reader))
+        let (reader, skip_compression, detection_error) = match options.skip_compression {
+            Some(skip_compression) => (reader, skip_compression, None),
+            None => detect_format(reader),
+        };
+        let reader: Box<dyn Read + 'a> = if skip_compression {
+            Box::new(BufReader::new(reader))
         } else {
-            reader
+            Box::new(MultiGzDecoder::new(reader))
         };
 
         SPZReader {
             reader,
+            offset: 0,
+            max_points: options.max_points,
+            max_uncompressed_size: options.max_uncompressed_size,
+            detection_error,
             header: None,
-            gaussians: None,
         }
     }
 
@@ -61,125 +199,295 @@ impl<'a> SPZReader<'a> {
         Ok(Self::new(Box::new(file), options))
     }
 
-    pub fn read(&mut self) -> Result<Vec<UnpackedGaussian>> {
+    pub fn read(&mut self) -> Result<GaussianCloud> {
         self.read_header()?;
-        self.read_gaussians()?;
-        Ok(self.gaussians.as_ref().unwrap().clone()) // TODO: Expensive clone.
+        self.read_gaussians()
+    }
+
+    /// Whether the header read so far has `SPZFlags::ANTIALIASED` set.
+    pub fn is_antialiased(&self) -> bool {
+        self.header.is_some_and(|header| header.is_antialiased())
     }
 
     pub fn read_header(&mut self) -> Result<SPZHeader> {
-        let mut header_bytes = [0; std::mem::size_of::<SPZHeader>()];
-        self.reader.my_read_exact(&mut header_bytes)?;
-        let header: SPZHeader = *bytemuck::from_bytes(&header_bytes);
-        if !header.is_valid() {
-            return Err(anyhow::anyhow!("Invalid header"));
+        if let Some(error) = self.detection_error.take() {
+            return Err(error);
         }
+        let header_bytes = self.read_column_data(Column::Header, size_of::<SPZHeader>())?;
+        let header: SPZHeader = bytemuck::pod_read_unaligned(&header_bytes);
+        header.validate()?;
+        check_limits(&header, self.max_points, self.max_uncompressed_size)?;
         self.header = Some(header);
         Ok(header)
     }
 
-    pub fn read_gaussians(&mut self) -> Result<Vec<UnpackedGaussian>> {
-        let header = self.header.as_ref().ok_or(anyhow::anyhow!("No header"))?;
+    pub fn read_gaussians(&mut self) -> Result<GaussianCloud> {
+        self.read_columns(Columns::all())
+    }
 
-        let mut position_data = vec![0; header.num_points as usize * 3 * 3];
-        self.reader.my_read_exact(&mut position_data)?;
-        let positions: Vec<Vec3<f32>> = position_data
-            .chunks_exact(3)
-            .map(|chunk| {
-                let chunk: [u8; 3] = chunk.try_into()?;
-                Ok(FixedPoint24::from(chunk, header.fractional_bits as usize).0)
-            })
-            .collect::<Result<Vec<_>>>()?
-            .chunks(3)
-            .map(|chunk| Vec3::new(chunk[0], chunk[1], chunk[2]))
-            .collect();
-        drop(position_data);
-
-        let mut alpha_data = vec![0; header.num_points as usize];
-        self.reader.my_read_exact(&mut alpha_data)?;
-        let alphas = alpha_data
-            .iter()
-            .map(|&v| inv_sigmoid(v as f32 / 255.0))
-            .collect::<Vec<_>>();
-        drop(alpha_data);
+    /// Decodes only the requested columns, reading the header first if needed. Unrequested columns
+    /// are left empty in the returned cloud. Unrequested columns that precede a requested one are
+    /// skipped without being decoded, and nothing after the last requested column is read. When
+    /// the last column is read, any data following it is an error.
+    pub fn read_columns(&mut self, columns: Columns) -> Result<GaussianCloud> {
+        let header = match self.header {
+            Some(header) => header,
+            None => self.read_header()?,
+        };
+        let num_points = header.num_points as usize;
 
-        let mut color_data = vec![0; header.num_points as usize * 3];
-        self.reader.my_read_exact(&mut color_data)?;
-        let colors = color_data
-            .iter()
-            .map(|&v| (v as f32 / 255.0 - 0.5) / COLOR_SCALE)
-            .collect::<Vec<_>>()
-            .chunks(3)
-            .map(|chunk| Vec3::new(chunk[0], chunk[1], chunk[2]))
-            .collect::<Vec<_>>();
-        drop(color_data);
+        let mut result = GaussianCloud {
+            num_points,
+            sh_degree: header.sh_degree,
+            antialiased: header.is_antialiased(),
+            ..Default::default()
+        };
+        let mut remaining = columns;
+        for (flag, column) in COLUMN_LAYOUT {
+            if remaining.is_empty() {
+                break;
+            }
+            let length = num_points * header.bytes_per_point(column);
+            if !remaining.contains(flag) {
+                self.skip_column_data(column, length)?;
+                continue;
+            }
+            remaining.remove(flag);
+            let data = self.read_column_data(column, length)?;
+            decode_column(&mut result, column, &data, &header);
+        }
+        if self.offset == header.expected_uncompressed_size() {
+            self.check_end(&header)?;
+        }
+        Ok(result)
+    }
+
+    /// Reads the quantized columns without decoding them, reading the header first if needed.
+    pub fn read_packed(&mut self) -> Result<PackedGaussians> {
+        let header = match self.header {
+            Some(header) => header,
+            None => self.read_header()?,
+        };
+        let num_points = header.num_points as usize;
+        let mut packed = PackedGaussians {
+            num_points,
+            ..PackedGaussians::from_header(&header)
+        };
+        for (_, column) in COLUMN_LAYOUT {
+            let data =
+                self.read_column_data(column, num_points * header.bytes_per_point(column))?;
+            match column {
+                Column::Positions => packed.positions = data,
+                Column::Alphas => packed.alphas = data,
+                Column::Colors => packed.colors = data,
+                Column::Scales => packed.scales = data,
+                Column::Rotations => packed.rotations = data,
+                Column::SphericalHarmonics => packed.spherical_harmonics = data,
+                Column::Header => unreachable!(),
+            }
+        }
+        self.check_end(&header)?;
+        Ok(packed)
+    }
+
+    fn read_column_data(&mut self, column: Column, length: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while data.len() < length {
+            let start = data.len();
+            data.resize(start + (length - start).min(READ_CHUNK_SIZE), 0);
+            self.reader
+                .read_column(&mut data[start..], column, self.offset + start)
+                .map_err(|error| match error {
+                    SpzError::Truncated { offset, actual, .. } => SpzError::Truncated {
+                        column,
+                        offset,
+                        expected: length,
+                        actual: start + actual,
+                    },
+                    error => error,
+                })?;
+        }
+        self.offset += length;
+        Ok(data)
+    }
+
+    /// Fails if the stream holds more data than `header` describes.
+    fn check_end(&mut self, header: &SPZHeader) -> Result<()> {
+        let mut byte = [0; 1];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {
+                    return Err(SpzError::TrailingData {
+                        expected: header.expected_uncompressed_size(),
+                    })
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-        let mut scale_data = vec![0; header.num_points as usize * 3];
-        self.reader.my_read_exact(&mut scale_data)?;
-        let scales = scale_data
+    fn skip_column_data(&mut self, column: Column, length: usize) -> Result<()> {
+        let skipped = std::io::copy(
+            &mut (&mut self.reader).take(length as u64),
+            &mut std::io::sink(),
+        )
+        .map_err(|source| SpzError::Read {
+            column,
+            offset: self.offset,
+            source,
+        })? as usize;
+        if skipped < length {
+            return Err(SpzError::Truncated {
+                column,
+                offset: self.offset + skipped,
+                expected: length,
+                actual: skipped,
+            });
+        }
+        self.offset += length;
+        Ok(())
+    }
+}
+
+/// Reads SPZ files written with `SPZWriterOptions::block_index`.
+///
+/// Only the blocks holding the requested columns are read and decompressed, on all available
+/// cores, so for example positions can be read without inflating the spherical harmonics.
+pub struct SPZBlockReader<R: Read + Seek> {
+    reader: R,
+    /// Where the gzip stream starts in `reader`.
+    start: u64,
+    index: BlockIndex,
+    pub header: SPZHeader,
+}
+
+impl<R: Read + Seek> SPZBlockReader<R> {
+    /// Reads the block index and the SPZ header from the gzip stream at the current position of
+    /// `reader`. Fails with `SpzError::MissingBlockIndex` if the stream has no index. Only the
+    /// limits of `options` are used.
+    pub fn new(mut reader: R, options: SPZReaderOptions) -> Result<Self> {
+        let start = reader.stream_position()?;
+        let index = BlockIndex::read_from(&mut reader)?.ok_or(SpzError::MissingBlockIndex)?;
+        let header_size = size_of::<SPZHeader>();
+        if index.blocks.first().map(|b| b.uncompressed_len as usize) != Some(header_size) {
+            return Err(SpzError::InvalidBlockIndex(
+                "the first block is not the header",
+            ));
+        }
+        let mut result = Self {
+            reader,
+            start,
+            index,
+            header: bytemuck::Zeroable::zeroed(),
+        };
+        let header_bytes = result.read_blocks(&[(0, 0)])?.remove(0);
+        let header: SPZHeader = bytemuck::pod_read_unaligned(&header_bytes);
+        header.validate()?;
+        check_limits(&header, options.max_points, options.max_uncompressed_size)?;
+        if result.index.uncompressed_len() != header.expected_uncompressed_size() as u64 {
+            return Err(SpzError::InvalidBlockIndex(
+                "the blocks don't match the size described by the header",
+            ));
+        }
+        result.header = header;
+        Ok(result)
+    }
+
+    pub fn read(&mut self) -> Result<GaussianCloud> {
+        self.read_columns(Columns::all())
+    }
+
+    /// Decodes only the requested columns. Unrequested columns are left empty in the returned
+    /// cloud.
+    pub fn read_columns(&mut self, columns: Columns) -> Result<GaussianCloud> {
+        let header = self.header;
+        let num_points = header.num_points as usize;
+
+        // The uncompressed range of each requested column.
+        let mut ranges = Vec::new();
+        let mut offset = size_of::<SPZHeader>() as u64;
+        for (flag, column) in COLUMN_LAYOUT {
+            let length = (num_points * header.bytes_per_point(column)) as u64;
+            if columns.contains(flag) {
+                ranges.push((column, offset..offset + length));
+            }
+            offset += length;
+        }
+
+        let mut needed = ranges
             .iter()
-            .map(|&v| (v as f32 / 16.0 - 10.0))
-            .collect::<Vec<_>>();
-        let scales = scales
-            .chunks(3)
-            .map(|chunk| Vec3::new(chunk[0], chunk[1], chunk[2]))
+            .flat_map(|(_, range)| self.overlapping_blocks(range))
             .collect::<Vec<_>>();
-        drop(scale_data);
+        needed.dedup();
+        let data = self.read_blocks(&needed)?;
 
-        let mut rotation_data = vec![0; header.num_points as usize * 3];
-        self.reader.my_read_exact(&mut rotation_data)?;
-        let rotations = rotation_data
-            .iter()
-            .map(|&v| v as f32)
-            .collect::<Vec<_>>()
-            .chunks(3)
-            .map(|v| {
-                let xyz = Vec3::new(v[0], v[1], v[2]) * 1.0 / 127.5 + Vec3::new(-1.0, -1.0, -1.0);
-                let w = f32::max(0.0, 1.0 - xyz.dot(xyz)).sqrt();
-                Quaternion::from_xyzw(xyz[0], xyz[1], xyz[2], w)
+        let mut result = GaussianCloud {
+            num_points,
+            sh_degree: header.sh_degree,
+            antialiased: header.is_antialiased(),
+            ..Default::default()
+        };
+        for (column, range) in &ranges {
+            let mut column_data = Vec::with_capacity((range.end - range.start) as usize);
+            for (i, block_start) in self.overlapping_blocks(range) {
+                let block = &data[needed.binary_search(&(i, block_start)).unwrap()];
+                let start = range.start.saturating_sub(block_start) as usize;
+                let end = (range.end - block_start).min(block.len() as u64) as usize;
+                column_data.extend_from_slice(&block[start..end]);
+            }
+            decode_column(&mut result, *column, &column_data, &header);
+        }
+        Ok(result)
+    }
+
+    /// The index and uncompressed offset of each block overlapping `range`.
+    fn overlapping_blocks(&self, range: &Range<u64>) -> Vec<(usize, u64)> {
+        self.index
+            .offsets()
+            .map(|(_, start)| start)
+            .zip(&self.index.blocks)
+            .enumerate()
+            .filter(|(_, (start, block))| {
+                *start < range.end && start + block.uncompressed_len as u64 > range.start
             })
-            .collect::<Vec<_>>();
-        drop(rotation_data);
-
-        let order = SphericalHarmonicsOrder::order_for_degree(header.sh_degree)
-            .ok_or(anyhow::anyhow!("Invalid SH degree"))?;
-        let spherical_harmonics = if order != SphericalHarmonicsOrder::Order0 {
-            let scalar_count = order.scalar_count();
-            let count = header.num_points as usize * scalar_count;
-            let mut spherical_harmonics_data = vec![0; count];
+            .map(|(i, (start, _))| (i, start))
+            .collect()
+    }
+
+    /// Reads and decompresses the given blocks, in parallel.
+    fn read_blocks(&mut self, blocks: &[(usize, u64)]) -> Result<Vec<Vec<u8>>> {
+        let offsets = self.index.offsets().collect::<Vec<_>>();
+        let mut compressed = Vec::with_capacity(blocks.len());
+        for &(i, _) in blocks {
+            let block = self.index.blocks[i];
             self.reader
-                .my_read_exact(&mut spherical_harmonics_data)
-                .context(format!("Failed to read SH data (wanted {} bytes)", count))?;
-            spherical_harmonics_data
-                .chunks(scalar_count)
-                .map(|chunk| SphericalHarmonics::from_spz_bytes(chunk.to_vec()))
-                .collect::<Vec<_>>()
-        } else {
-            vec![SphericalHarmonics::default(); header.num_points as usize]
-        };
+                .seek(SeekFrom::Start(self.start + offsets[i].0))?;
+            let mut member = Vec::new();
+            (&mut self.reader)
+                .take(block.compressed_len as u64)
+                .read_to_end(&mut member)?;
+            if member.len() != block.compressed_len as usize {
+                return Err(SpzError::InvalidBlockIndex("a block extends past the end"));
+            }
+            compressed.push((member, block.uncompressed_len as usize));
+        }
 
-        let gaussians: Vec<UnpackedGaussian> = itertools::izip!(
-            positions.iter(),
-            scales.iter(),
-            rotations.iter(),
-            alphas.iter(),
-            colors.iter(),
-            spherical_harmonics.iter()
-        )
-        .map({
-            |(position, scale, rotation, alpha, color, spherical_harmonics)| UnpackedGaussian {
-                position: *position,
-                scales: *scale,
-                rotation: *rotation,
-                alpha: *alpha,
-                color: *color,
-                spherical_harmonics: *spherical_harmonics,
+        let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        parallel_map(&compressed, threads, |(member, uncompressed_len)| {
+            let mut data = Vec::new();
+            GzDecoder::new(member.as_slice())
+                .take(*uncompressed_len as u64 + 1)
+                .read_to_end(&mut data)?;
+            if data.len() != *uncompressed_len {
+                return Err(SpzError::InvalidBlockIndex(
+                    "a block doesn't decompress to its indexed size",
+                ));
             }
+            Ok(data)
         })
-        .collect();
-
-        self.gaussians = Some(gaussians);
-
-        Ok(self.gaussians.as_ref().unwrap().clone()) // TODO: Expensive clone.
+        .into_iter()
+        .collect()
     }
 }
@@ -1,15 +1,66 @@
-use anyhow::Result;
-use std::io::Write;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
 
+use crate::error::{Result, SpzError};
 use crate::fixedpoint24::{compute_fixed_point_fractional_bits, FixedPoint24};
-use crate::spherical_harmonics::SphericalHarmonicsOrder;
+use crate::gaussian_cloud::{GaussianCloud, ShDegreePolicy};
+use crate::packed_gaussians::PackedGaussians;
+use crate::spz_format::{check_fractional_bits, SPZFlags, SPZHeader, SH1_BITS, SH_REST_BITS};
 use crate::support::sigmoid;
 use crate::unpacked_gaussian::UnpackedGaussian;
+use vek::{Vec3, Vec4};
 
-use crate::spz_format::*;
-
+#[derive(Debug, Clone)]
 pub struct SPZWriterOptions {
-    pub omit_spherical_harmonics: bool,
+    /// The highest spherical harmonics degree to write, 0 to 3. Clouds with a higher degree have
+    /// their coefficients truncated, and the reduced degree is written in the header.
+    pub max_sh_degree: u8,
+    /// The SPZ version to write. Version 2 stores rotations as 3 bytes of xyz, version 3 uses the
+    /// more precise "smallest three" encoding in 4 bytes.
+    pub version: u32,
+    /// Bits kept per band 1 spherical harmonics coefficient, 1 to 8. Defaults to 5, like the
+    /// reference encoder.
+    pub sh1_bits: u8,
+    /// Bits kept per coefficient of the higher bands, 1 to 8. Defaults to 4.
+    pub sh_rest_bits: u8,
+    /// The fractional bits of the 24 bit fixed point positions, 0 to 23. `None`, the default,
+    /// picks the most precise value that still fits the largest coordinate, so positions differ
+    /// from the reference encoder's, which always uses 12. `Some(12)` reproduces them exactly.
+    /// Coordinates that don't fit are clamped, see `WriteReport::clipped_points`.
+    pub fractional_bits: Option<u8>,
+    /// The gzip compression level used by `write_spz`, 0 (none) to 9 (best). Defaults to 9.
+    pub compression_level: u32,
+    /// The number of threads `write_spz` compresses with, or 0 for all available cores. With more
+    /// than one thread the output is multi-member gzip. Defaults to 1.
+    pub compression_threads: usize,
+    /// Whether `write_spz` splits compressed output into independently compressed blocks with an
+    /// index, so `SPZBlockReader` can decompress columns in parallel or skip straight to one. The
+    /// output is still ordinary multi-member gzip. Defaults to false.
+    pub block_index: bool,
+    /// How `SPZStreamWriter` reconciles batches with different spherical harmonics degrees.
+    /// Defaults to `ShDegreePolicy::Strict`.
+    pub sh_degree_policy: ShDegreePolicy,
+    /// Whether gaussians pushed with `SPZStreamWriter::push` are antialiased. Clouds carry their
+    /// own flag, so `SPZWriter::write` and `SPZStreamWriter::push_cloud` ignore this. Defaults to
+    /// false.
+    pub antialiased: bool,
+}
+
+impl Default for SPZWriterOptions {
+    fn default() -> Self {
+        Self {
+            max_sh_degree: 3,
+            version: 3,
+            sh1_bits: SH1_BITS,
+            sh_rest_bits: SH_REST_BITS,
+            fractional_bits: None,
+            compression_level: 9,
+            compression_threads: 1,
+            block_index: false,
+            sh_degree_policy: ShDegreePolicy::Strict,
+            antialiased: false,
+        }
+    }
 }
 
 pub struct SPZWriter<W: Write> {
@@ -25,101 +76,477 @@ where
         Self { writer, options }
     }
 
-    pub fn write(&mut self, gaussians: &Vec<UnpackedGaussian>) -> Result<()> {
-        let sh_count = gaussians
-            .iter()
-            .map(|g| g.spherical_harmonics.order().index())
-            .collect::<std::collections::HashSet<_>>();
+    /// Quantizes and writes a cloud, and reports how much precision was lost.
+    pub fn write(&mut self, cloud: &GaussianCloud) -> Result<WriteReport> {
+        let packed = PackedGaussians::pack(cloud, &self.options)?;
+        self.write_columns(&packed)?;
+        WriteReport::new(cloud, &packed)
+    }
 
-        if sh_count.len() != 1 {
-            return Err(anyhow::anyhow!(
-                "All gaussians must have the same spherical harmonic degree"
-            ));
-        }
-        let sh_count = *sh_count
-            .iter()
-            .next()
-            .ok_or(anyhow::anyhow!("No spherical harmonics"))?;
+    /// Writes already quantized gaussians as is. `SPZWriterOptions` are ignored, since the packed
+    /// gaussians carry their own version and header fields.
+    pub fn write_packed(&mut self, packed: &PackedGaussians) -> Result<()> {
+        packed.validate()?;
+        self.write_columns(packed)
+    }
 
-        let order = SphericalHarmonicsOrder::order_for_degree(sh_count as u8);
+    fn write_columns(&mut self, packed: &PackedGaussians) -> Result<()> {
+        self.writer
+            .write_all(bytemuck::bytes_of(&packed.header()))?;
+        self.writer.write_all(&packed.positions)?;
+        self.writer.write_all(&packed.alphas)?;
+        self.writer.write_all(&packed.colors)?;
+        self.writer.write_all(&packed.scales)?;
+        self.writer.write_all(&packed.rotations)?;
+        self.writer.write_all(&packed.spherical_harmonics)?;
+        Ok(())
+    }
+}
 
-        let positions: Vec<f32> = gaussians
+/// How closely a written cloud matches the cloud it was written from.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WriteReport {
+    /// The fractional bits positions were stored with.
+    pub fractional_bits: u8,
+    /// The indices of gaussians with a position coordinate outside the fixed point range, which
+    /// was clamped.
+    pub clipped_points: Vec<usize>,
+    pub max_error: QuantizationError,
+}
+
+/// The largest absolute difference between an input value and its decoded value, per attribute.
+/// Alphas are compared as opacities, after the sigmoid, and rotations as normalized quaternions.
+/// Spherical harmonics above the written degree are not compared.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct QuantizationError {
+    pub positions: f32,
+    pub alphas: f32,
+    pub colors: f32,
+    pub scales: f32,
+    pub rotations: f32,
+    pub spherical_harmonics: f32,
+}
+
+impl WriteReport {
+    /// Compares `cloud` with `packed`, the result of packing it.
+    pub fn new(cloud: &GaussianCloud, packed: &PackedGaussians) -> Result<Self> {
+        let fractional_bits = packed.fractional_bits as usize;
+        let clipped_points = cloud
+            .positions
             .iter()
-            .flat_map(|g| g.position.iter())
-            .cloned()
+            .enumerate()
+            .filter(|(_, p)| {
+                p.iter()
+                    .any(|&v| FixedPoint24::new(v).is_clipped(fractional_bits))
+            })
+            .map(|(index, _)| index)
             .collect();
-        let fractional_bits = compute_fixed_point_fractional_bits(&positions, 24);
 
-        let sh_degree = order.ok_or(anyhow::anyhow!("Invalid SH degree"))?.index() as u8;
+        let cloud = cloud.with_max_sh_degree(packed.sh_degree)?;
+        let decoded = packed.unpack()?;
+        let flat = |v: &[Vec3<f32>]| v.iter().flat_map(|v| v.into_array()).collect::<Vec<_>>();
+        let max_error = QuantizationError {
+            positions: max_difference(&flat(&cloud.positions), &flat(&decoded.positions)),
+            alphas: max_difference(
+                &cloud.alphas.iter().map(|&a| sigmoid(a)).collect::<Vec<_>>(),
+                &decoded
+                    .alphas
+                    .iter()
+                    .map(|&a| sigmoid(a))
+                    .collect::<Vec<_>>(),
+            ),
+            colors: max_difference(&flat(&cloud.colors), &flat(&decoded.colors)),
+            scales: max_difference(&flat(&cloud.scales), &flat(&decoded.scales)),
+            rotations: cloud
+                .rotations
+                .iter()
+                .zip(&decoded.rotations)
+                .map(|(a, b)| {
+                    let (a, b) = (a.normalized().into_vec4(), b.normalized().into_vec4());
+                    // q and -q are the same rotation.
+                    let difference = |d: Vec4<f32>| d.map(f32::abs).reduce_partial_max();
+                    difference(a - b).min(difference(a + b))
+                })
+                .fold(0.0, f32::max),
+            spherical_harmonics: max_difference(
+                &cloud.spherical_harmonics,
+                &decoded.spherical_harmonics,
+            ),
+        };
 
-        let header = SPZHeader::new(gaussians.len() as u32, sh_degree, fractional_bits as u8, 0);
-        self.writer.write_all(bytemuck::bytes_of(&header))?;
+        Ok(Self {
+            fractional_bits: packed.fractional_bits,
+            clipped_points,
+            max_error,
+        })
+    }
+}
 
-        let mut position_data: Vec<u8> = Vec::new();
-        for gaussian in gaussians {
-            for &v in &gaussian.position {
-                let f = FixedPoint24::new(v);
-                position_data.extend_from_slice(&f.into(fractional_bits));
-            }
+fn max_difference(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0, f32::max)
+}
+
+/// Writes gaussians pushed in batches, so clouds larger than memory can be encoded.
+///
+/// Each column is spilled to its own temporary file as batches arrive, and the files are joined
+/// into the SPZ layout by `finish`. Unless `SPZWriterOptions::fractional_bits` is set, positions
+/// are spilled as `f32`s, since their fixed point precision depends on the extent of the whole
+/// cloud. The output is identical to what `SPZWriter` writes for the same gaussians: uncompressed
+/// SPZ, so `W` must be wrapped in a gzip encoder, like `flate2::write::GzEncoder`, to produce a
+/// valid `.spz` file.
+pub struct SPZStreamWriter<W: Write> {
+    writer: W,
+    options: SPZWriterOptions,
+    num_points: usize,
+    /// The degree of the first batch pushed, which later batches are reconciled to with
+    /// `SPZWriterOptions::sh_degree_policy`.
+    input_sh_degree: Option<u8>,
+    sh_degree: u8,
+    /// Whether every non-empty batch pushed so far was antialiased, as in
+    /// `GaussianCloud::concatenate`.
+    antialiased: Option<bool>,
+    max_position: f32,
+    positions: BufWriter<File>,
+    alphas: BufWriter<File>,
+    colors: BufWriter<File>,
+    scales: BufWriter<File>,
+    rotations: BufWriter<File>,
+    spherical_harmonics: BufWriter<File>,
+}
+
+impl<W> SPZStreamWriter<W>
+where
+    W: Write,
+{
+    pub fn new(writer: W, options: SPZWriterOptions) -> Result<Self> {
+        // Checked up front, in case no gaussians are ever pushed.
+        if !(2..=3).contains(&options.version) {
+            return Err(SpzError::UnsupportedVersion(options.version));
         }
-        assert!(position_data.len() == &gaussians.len() * 3 * 3);
-        self.writer.write_all(&position_data)?;
-        drop(position_data);
-
-        let mut alpha_data = Vec::new();
-        for gaussian in gaussians {
-            let v = (sigmoid(gaussian.alpha) * 255.0) as u8;
-            alpha_data.push(v);
+        check_fractional_bits(options.fractional_bits)?;
+        let spill = || -> Result<BufWriter<File>> { Ok(BufWriter::new(tempfile::tempfile()?)) };
+        Ok(Self {
+            writer,
+            options,
+            num_points: 0,
+            input_sh_degree: None,
+            sh_degree: 0,
+            antialiased: None,
+            max_position: 0.0,
+            positions: spill()?,
+            alphas: spill()?,
+            colors: spill()?,
+            scales: spill()?,
+            rotations: spill()?,
+            spherical_harmonics: spill()?,
+        })
+    }
+
+    /// Encodes a batch of gaussians. Gaussians with different spherical harmonics degrees are
+    /// reconciled with `SPZWriterOptions::sh_degree_policy`, and the batch is antialiased if
+    /// `SPZWriterOptions::antialiased` is set.
+    pub fn push(&mut self, gaussians: &[UnpackedGaussian]) -> Result<()> {
+        let mut cloud =
+            GaussianCloud::from_gaussians_with_policy(gaussians, self.options.sh_degree_policy)?;
+        cloud.antialiased = self.options.antialiased;
+        self.push_cloud(&cloud)
+    }
+
+    /// Encodes a batch of gaussians. Batches after the first are converted to the first batch's
+    /// spherical harmonics degree, if `SPZWriterOptions::sh_degree_policy` allows it. The output
+    /// is only flagged as antialiased if every batch is.
+    pub fn push_cloud(&mut self, cloud: &GaussianCloud) -> Result<()> {
+        if cloud.is_empty() {
+            return Ok(());
         }
-        assert!(alpha_data.len() == gaussians.len());
-        self.writer.write_all(&alpha_data)?;
-        drop(alpha_data);
-
-        let mut color_data = Vec::new();
-        for gaussian in gaussians {
-            for &v in &gaussian.color {
-                let v = (((v * COLOR_SCALE) + 0.5) * 255.0) as u8;
-                color_data.push(v);
-            }
+        let sh_degree = *self.input_sh_degree.get_or_insert(cloud.sh_degree);
+        let policy = self.options.sh_degree_policy;
+        let allowed = match policy {
+            ShDegreePolicy::Strict => cloud.sh_degree == sh_degree,
+            ShDegreePolicy::Promote => cloud.sh_degree <= sh_degree,
+            ShDegreePolicy::Truncate => cloud.sh_degree >= sh_degree,
+        };
+        if !allowed {
+            return Err(SpzError::ShDegreeConflict {
+                policy,
+                expected: sh_degree,
+                actual: cloud.sh_degree,
+            });
         }
-        assert!(color_data.len() == gaussians.len() * 3);
-        self.writer.write_all(&color_data)?;
-        drop(color_data);
-
-        let mut scale_data = Vec::new();
-        for gaussian in gaussians {
-            for &v in &gaussian.scales {
-                let v = ((v + 10.0) * 16.0) as u8;
-                scale_data.push(v);
+        let cloud = cloud.with_sh_degree(sh_degree)?;
+        let num_points = self.num_points + cloud.num_points;
+        if num_points > u32::MAX as usize {
+            return Err(SpzError::TooManyPoints {
+                num_points,
+                max: u32::MAX as usize,
+            });
+        }
+
+        let packed = PackedGaussians::pack_without_positions(&cloud, &self.options)?;
+        self.sh_degree = packed.sh_degree;
+        self.antialiased = Some(self.antialiased.unwrap_or(true) && cloud.antialiased);
+        for position in &cloud.positions {
+            for &v in position {
+                match self.options.fractional_bits {
+                    Some(bits) => self
+                        .positions
+                        .write_all(&FixedPoint24::new(v).into(bits.into()))?,
+                    None => {
+                        self.max_position = self.max_position.max(v.abs());
+                        self.positions.write_all(&v.to_le_bytes())?;
+                    }
+                }
             }
         }
-        assert!(scale_data.len() == gaussians.len() * 3);
-        self.writer.write_all(&scale_data)?;
-        drop(scale_data);
-
-        let mut rotation_data = Vec::new();
-        for gaussian in gaussians {
-            let q = &gaussian.rotation.normalized();
-            for v in q.into_vec3() {
-                let v = v * if q.w < 0.0 { -127.5 } else { 127.5 };
-                let v = v + 127.5;
-                rotation_data.push(v as u8);
+        self.alphas.write_all(&packed.alphas)?;
+        self.colors.write_all(&packed.colors)?;
+        self.scales.write_all(&packed.scales)?;
+        self.rotations.write_all(&packed.rotations)?;
+        self.spherical_harmonics
+            .write_all(&packed.spherical_harmonics)?;
+        self.num_points = num_points;
+        Ok(())
+    }
+
+    /// Writes the header and all columns, uncompressed, and returns the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        let fractional_bits = self.options.fractional_bits.map_or_else(
+            || compute_fixed_point_fractional_bits(&[self.max_position], 24),
+            usize::from,
+        );
+        let mut flags = SPZFlags::empty();
+        flags.set(SPZFlags::ANTIALIASED, self.antialiased.unwrap_or(false));
+        let header = SPZHeader::new(
+            self.options.version,
+            self.num_points as u32,
+            self.sh_degree,
+            fractional_bits as u8,
+            flags,
+        );
+        self.writer.write_all(bytemuck::bytes_of(&header))?;
+
+        let mut positions = BufReader::new(rewind(self.positions)?);
+        if self.options.fractional_bits.is_some() {
+            std::io::copy(&mut positions, &mut self.writer)?;
+        } else {
+            let mut bytes = [0; 4];
+            for _ in 0..self.num_points * 3 {
+                positions.read_exact(&mut bytes)?;
+                let v = f32::from_le_bytes(bytes);
+                self.writer
+                    .write_all(&FixedPoint24::new(v).into(fractional_bits))?;
             }
         }
-        assert!(rotation_data.len() == gaussians.len() * 3);
-        self.writer.write_all(&rotation_data)?;
-        drop(rotation_data);
-
-        if !self.options.omit_spherical_harmonics {
-            let mut sh_data = Vec::new();
-            for gaussian in gaussians {
-                let bytes = gaussian.spherical_harmonics.spz_bytes();
-                sh_data.extend_from_slice(&bytes);
+
+        for column in [
+            self.alphas,
+            self.colors,
+            self.scales,
+            self.rotations,
+            self.spherical_harmonics,
+        ] {
+            std::io::copy(&mut rewind(column)?, &mut self.writer)?;
+        }
+        Ok(self.writer)
+    }
+}
+
+/// Flushes a spill file and seeks back to its start.
+fn rewind(spill: BufWriter<File>) -> Result<File> {
+    let mut file = spill.into_inner().map_err(|e| e.into_error())?;
+    file.rewind()?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spherical_harmonics::SphericalHarmonics;
+    use vek::{Quaternion, Vec3};
+
+    fn gaussian(n: f32) -> UnpackedGaussian {
+        UnpackedGaussian {
+            position: Vec3::new(n, -2.0 * n, 0.5),
+            rotation: Quaternion::from_xyzw(0.1 * n, 0.2, 0.3, 1.0),
+            scales: Vec3::broadcast(n / 10.0),
+            color: Vec3::broadcast(n / 20.0),
+            alpha: n / 5.0,
+            spherical_harmonics: SphericalHarmonics::Order1([Vec3::broadcast(n / 30.0); 3]),
+        }
+    }
+
+    #[test]
+    fn test_stream_writer() {
+        let gaussians = (0..10).map(|n| gaussian(n as f32)).collect::<Vec<_>>();
+        let options = || SPZWriterOptions {
+            version: 2,
+            ..Default::default()
+        };
+
+        let mut expected = Vec::new();
+        SPZWriter::new(&mut expected, options())
+            .write(&GaussianCloud::from_gaussians(&gaussians).unwrap())
+            .unwrap();
+
+        let mut writer = SPZStreamWriter::new(Vec::new(), options()).unwrap();
+        for batch in gaussians.chunks(3) {
+            writer.push(batch).unwrap();
+        }
+        writer.push(&[]).unwrap();
+        assert_eq!(writer.finish().unwrap(), expected);
+
+        let mut writer = SPZStreamWriter::new(Vec::new(), options()).unwrap();
+        writer.push(&gaussians[..1]).unwrap();
+        assert!(matches!(
+            writer.push(&[UnpackedGaussian::default()]),
+            Err(SpzError::ShDegreeConflict {
+                policy: ShDegreePolicy::Strict,
+                expected: 1,
+                actual: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn test_stream_writer_policy() {
+        let degree0 = UnpackedGaussian::default();
+        let degree1 = gaussian(2.0);
+        let options = |sh_degree_policy| SPZWriterOptions {
+            sh_degree_policy,
+            ..Default::default()
+        };
+        let write = |policy, gaussians: &[UnpackedGaussian]| {
+            let cloud = GaussianCloud::from_gaussians_with_policy(gaussians, policy).unwrap();
+            let mut bytes = Vec::new();
+            SPZWriter::new(&mut bytes, options(policy))
+                .write(&cloud)
+                .unwrap();
+            bytes
+        };
+
+        // A mixed first batch is reconciled within itself, later ones to its degree.
+        let mut writer =
+            SPZStreamWriter::new(Vec::new(), options(ShDegreePolicy::Promote)).unwrap();
+        writer.push(&[degree0, degree1]).unwrap();
+        writer.push(&[degree0]).unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            write(ShDegreePolicy::Promote, &[degree0, degree1, degree0])
+        );
+        let mut writer =
+            SPZStreamWriter::new(Vec::new(), options(ShDegreePolicy::Promote)).unwrap();
+        writer.push(&[degree0]).unwrap();
+        assert!(matches!(
+            writer.push(&[degree1]),
+            Err(SpzError::ShDegreeConflict {
+                policy: ShDegreePolicy::Promote,
+                expected: 0,
+                actual: 1
+            })
+        ));
+
+        let mut writer =
+            SPZStreamWriter::new(Vec::new(), options(ShDegreePolicy::Truncate)).unwrap();
+        writer.push(&[degree0]).unwrap();
+        writer.push(&[degree1]).unwrap();
+        assert_eq!(
+            writer.finish().unwrap(),
+            write(ShDegreePolicy::Truncate, &[degree0, degree1])
+        );
+
+        // The antialiased flag is set only if every batch is antialiased.
+        let mut antialiased = GaussianCloud::from_gaussians(&[degree1]).unwrap();
+        antialiased.antialiased = true;
+        let flags = |batches: &[&GaussianCloud]| {
+            let mut writer = SPZStreamWriter::new(Vec::new(), options(Default::default())).unwrap();
+            for batch in batches {
+                writer.push_cloud(batch).unwrap();
             }
-            self.writer.write_all(&sh_data)?;
-            drop(sh_data);
+            writer.finish().unwrap()[14]
+        };
+        assert_eq!(
+            flags(&[&antialiased, &antialiased]),
+            SPZFlags::ANTIALIASED.bits()
+        );
+        let plain = GaussianCloud::from_gaussians(&[degree1]).unwrap();
+        assert_eq!(flags(&[&antialiased, &plain]), 0);
+        assert_eq!(
+            flags(&[&antialiased, &GaussianCloud::default()]),
+            SPZFlags::ANTIALIASED.bits()
+        );
+        assert_eq!(flags(&[]), 0);
+
+        let mut writer = SPZStreamWriter::new(
+            Vec::new(),
+            SPZWriterOptions {
+                antialiased: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        writer.push(&[degree1]).unwrap();
+        assert_eq!(writer.finish().unwrap()[14], SPZFlags::ANTIALIASED.bits());
+    }
+
+    #[test]
+    fn test_write_report() {
+        let gaussians = (0..10).map(|n| gaussian(n as f32)).collect::<Vec<_>>();
+        let cloud = GaussianCloud::from_gaussians(&gaussians).unwrap();
+
+        let report = SPZWriter::new(Vec::new(), SPZWriterOptions::default())
+            .write(&cloud)
+            .unwrap();
+        // The largest coordinate is 18, which needs 5 integer bits.
+        assert_eq!(report.fractional_bits, 18);
+        assert!(report.clipped_points.is_empty());
+        let error = report.max_error;
+        assert!(error.positions <= 0.5 / (1 << 18) as f32);
+        assert!(error.alphas < 0.51 / 255.0);
+        assert!(error.colors > 0.0 && error.colors < 0.02);
+        assert!(error.scales > 0.0 && error.scales <= 0.5 / 16.0);
+        assert!(error.rotations < 0.01);
+        assert!(error.spherical_harmonics > 0.0 && error.spherical_harmonics < 0.05);
+
+        // A largest coordinate that is a power of two must not be clamped.
+        let power_of_two = GaussianCloud::from_gaussians(&[gaussian(8.0), gaussian(1.0)]).unwrap();
+        let report = SPZWriter::new(Vec::new(), SPZWriterOptions::default())
+            .write(&power_of_two)
+            .unwrap();
+        assert_eq!(report.fractional_bits, 18);
+        assert!(report.clipped_points.is_empty());
+        assert!(report.max_error.positions <= 0.5 / (1 << 18) as f32);
+
+        // With 20 fractional bits only coordinates in [-8, 8) fit.
+        let options = || SPZWriterOptions {
+            fractional_bits: Some(20),
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        let report = SPZWriter::new(&mut bytes, options()).write(&cloud).unwrap();
+        assert_eq!(report.fractional_bits, 20);
+        assert_eq!(report.clipped_points, (5..10).collect::<Vec<_>>());
+        assert_eq!(report.max_error.positions, 10.0);
+
+        // Positions are packed as they are pushed when the fractional bits are fixed.
+        let mut writer = SPZStreamWriter::new(Vec::new(), options()).unwrap();
+        for batch in gaussians.chunks(4) {
+            writer.push(batch).unwrap();
         }
+        assert_eq!(writer.finish().unwrap(), bytes);
 
-        Ok(())
+        assert!(matches!(
+            SPZWriter::new(
+                Vec::new(),
+                SPZWriterOptions {
+                    fractional_bits: Some(24),
+                    ..Default::default()
+                }
+            )
+            .write(&cloud),
+            Err(SpzError::InvalidFractionalBits(24))
+        ));
     }
 }
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use anyhow::Result;
+use crate::error::{Column, Result, SpzError};
 use std::io::Read;
 
 pub(crate) fn linear_to_log(v: f32) -> f32 {
@@ -36,17 +36,62 @@ pub(crate) fn inv_sigmoid(x: f32) -> f32 {
     f32::ln(x / (1.0 - x))
 }
 
+/// Rounds to the nearest byte, clamping out of range values, like the reference `toUint8`.
+pub(crate) fn to_u8(x: f32) -> u8 {
+    x.round().clamp(0.0, 255.0) as u8
+}
+
+// ---- Half floats ----
+
+/// Converts an IEEE 754 half-precision float (as stored by version 1 SPZ files) to an `f32`.
+pub(crate) fn half_to_float(h: u16) -> f32 {
+    let sign = ((h >> 15) & 0x1) as u32;
+    let exponent = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+
+    let bits = match (exponent, mantissa) {
+        // Signed zero.
+        (0, 0) => sign << 31,
+        // Subnormal: normalize the mantissa into an f32 exponent.
+        (0, _) => {
+            let mut exponent = 127 - 15 + 1;
+            let mut mantissa = mantissa;
+            while mantissa & 0x400 == 0 {
+                mantissa <<= 1;
+                exponent -= 1;
+            }
+            (sign << 31) | (exponent << 23) | ((mantissa & 0x3ff) << 13)
+        }
+        // Infinity or NaN.
+        (0x1f, _) => (sign << 31) | (0xff << 23) | (mantissa << 13),
+        _ => (sign << 31) | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
 pub(crate) trait ReadExt: Read {
-    fn my_read_exact(&mut self, buffer: &mut [u8]) -> Result<()> {
+    /// Like `read_exact`, but reports which column ran out of data and where.
+    fn read_column(&mut self, buffer: &mut [u8], column: Column, offset: usize) -> Result<()> {
         let mut total_read = 0;
         while total_read < buffer.len() {
-            let count = self.read(&mut buffer[total_read..])?;
+            let count = match self.read(&mut buffer[total_read..]) {
+                Ok(count) => count,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(source) => {
+                    return Err(SpzError::Read {
+                        column,
+                        offset: offset + total_read,
+                        source,
+                    })
+                }
+            };
             if count == 0 {
-                return Err(anyhow::anyhow!(format!(
-                    "Failed to read {} bytes, got only {} bytes instead.",
-                    buffer.len(),
-                    total_read
-                )));
+                return Err(SpzError::Truncated {
+                    column,
+                    offset: offset + total_read,
+                    expected: buffer.len(),
+                    actual: total_read,
+                });
             }
             total_read += count;
         }
@@ -55,3 +100,21 @@ pub(crate) trait ReadExt: Read {
 }
 
 impl<R: Read + ?Sized> ReadExt for R {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_half_to_float() {
+        assert_eq!(half_to_float(0x0000), 0.0);
+        assert_eq!(half_to_float(0x3c00), 1.0);
+        assert_eq!(half_to_float(0xc000), -2.0);
+        assert_eq!(half_to_float(0x5640), 100.0);
+        assert_eq!(half_to_float(0xd640), -100.0);
+        assert_eq!(half_to_float(0x7bff), 65504.0);
+        assert_eq!(half_to_float(0x0001), 2.0f32.powi(-24));
+        assert_eq!(half_to_float(0x7c00), f32::INFINITY);
+        assert!(half_to_float(0x7e00).is_nan());
+    }
+}
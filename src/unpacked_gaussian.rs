@@ -48,7 +48,7 @@ impl UnpackedGaussian {
             self.alpha,
         ];
         scalars.extend(self.spherical_harmonics.scalars());
-        return scalars;
+        scalars
     }
 
     pub fn is_valid(&self) -> bool {
@@ -61,6 +61,6 @@ impl UnpackedGaussian {
                 return false;
             }
         }
-        return true;
+        true
     }
 }